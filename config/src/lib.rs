@@ -20,26 +20,111 @@ pub mod sbi {
     // system reset extension
     pub const SRST_EXTENSION: usize = 0x53525354;
     pub const SBI_SHUTDOWN: usize = 0;
+
+    // hart state management extension
+    pub const HSM_EXTENSION: usize = 0x48534D;
+    pub const SBI_HART_START: usize = 0;
+}
+
+/// The syscalls: number, name, typed arguments and return type of each.
+/// It expands to `$m! { entries }`, so the kernel dispatcher, the ulib
+/// wrappers and the `SYSCALL_*` numbers are all generated from it.
+/// Arguments and results go through a0-a5 as `syscall::Reg` values,
+/// at most 6 of them, and user addresses are passed as usize.
+#[macro_export]
+#[rustfmt::skip]
+macro_rules! syscall_table {
+    ($m:ident) => {
+        $m! {
+            /// end the calling thread only
+            SYSCALL_EXIT = 93 => fn exit(code: i32) -> ();
+            /// end the process, with all its threads
+            SYSCALL_EXIT_GROUP = 94 => fn exit_group(code: i32) -> ();
+            /// FUTEX_WAIT while *uaddr == val, or FUTEX_WAKE up to val threads
+            SYSCALL_FUTEX = 98 => fn futex(uaddr: usize, op: usize, val: usize) -> usize;
+            SYSCALL_GETTID = 178 => fn gettid() -> usize;
+            SYSCALL_KILL = 129 => fn kill(pid: usize, sig: usize) -> ();
+            /// set the handler of sig, return the old one
+            SYSCALL_SIGACTION = 134 => fn sigaction(sig: usize, handler: usize, restorer: usize) -> usize;
+            /// resume the code a handler interrupted, restoring all registers
+            SYSCALL_SIGRETURN = 139 => fn sigreturn() -> usize;
+            /// start a thread at an entry point with its own stack, not Linux's clone
+            SYSCALL_CLONE = 401 => fn clone(entry: usize, sp: usize, arg: usize) -> usize;
+            SYSCALL_WRITE = 64 => fn write(fd: usize, buf: usize, len: usize) -> usize;
+            SYSCALL_READ = 63 => fn read(fd: usize, buf: usize, len: usize) -> usize;
+            /// path is '\0' terminated
+            SYSCALL_OPEN = 56 => fn open(path: usize, flags: usize) -> usize;
+            SYSCALL_CLOSE = 57 => fn close(fd: usize) -> ();
+            SYSCALL_DUP = 23 => fn dup(fd: usize) -> usize;
+            SYSCALL_DUP2 = 24 => fn dup2(oldfd: usize, newfd: usize) -> usize;
+            /// store the read and write ends in fds[0] and fds[1]
+            SYSCALL_PIPE = 59 => fn pipe(fds: usize) -> ();
+            /// give up the CPU to the other ready processes
+            SYSCALL_YIELD = 124 => fn sched_yield() -> ();
+            SYSCALL_FORK = 220 => fn fork() -> usize;
+            /// path and args are '\0' terminated, argv ends with a null pointer
+            SYSCALL_EXEC = 221 => fn exec(path: usize, argv: usize) -> usize;
+            /// pid -1 waits for any child, status may be null
+            SYSCALL_WAITPID = 260 => fn waitpid(pid: isize, status: usize) -> usize;
+            SYSCALL_GETPID = 172 => fn getpid() -> usize;
+            /// fail with EINTR on a signal
+            SYSCALL_SLEEP = 101 => fn sleep(ticks: usize) -> ();
+            /// fail with EINTR on a signal, storing the nanoseconds left at rem unless null
+            SYSCALL_NANOSLEEP = 115 => fn nanosleep(ns: usize, rem: usize) -> ();
+            SYSCALL_SBARK = 400 => fn sbrk(increment: isize) -> usize;
+            SYSCALL_GETTIME = 169 => fn gettime() -> usize;
+            /// store the '\0' terminated current directory in buf, return its size
+            SYSCALL_GETCWD = 17 => fn getcwd(buf: usize, size: usize) -> usize;
+            /// pid 0 is the caller
+            SYSCALL_SETPRIORITY = 140 => fn setpriority(pid: usize, nice: i32) -> ();
+            SYSCALL_MMAP = 222 => fn mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> usize;
+            SYSCALL_MUNMAP = 215 => fn munmap(addr: usize, len: usize) -> ();
+            SYSCALL_MPROTECT = 226 => fn mprotect(addr: usize, len: usize, prot: usize) -> ();
+            /// log the syscalls of the process and its children if on is not 0
+            SYSCALL_TRACE = 402 => fn trace(on: usize) -> ();
+        }
+    };
 }
 
 /// Interface of operating system and applications
 #[rustfmt::skip]
 pub mod syscall {
-    /// syscall number
-    pub const SYSCALL_EXIT: usize     = 93;
-    pub const SYSCALL_WRITE: usize    = 64;
-    pub const SYSCALL_READ: usize     = 63;
-    pub const SYSCALL_OPEN: usize     = 56;
-    pub const SYSCALL_CLOSE: usize    = 57;
-    pub const SYSCALL_YIELD: usize    = 124;
-    pub const SYSCALL_FORK: usize     = 220;
-    pub const SYSCALL_EXEC: usize     = 221;
-    pub const SYSCALL_WAITPID: usize  = 260;
-    pub const SYSCALL_GETPID: usize   = 172;
-    pub const SYSCALL_SLEEP: usize    = 101;
-    pub const SYSCALL_SBARK: usize    = 400;
-    pub const SYSCALL_GETTIME: usize  = 169;
-    pub const SYSCALL_GETCWD: usize   = 17;
+    /// syscall numbers
+    macro_rules! numbers {
+        ($($(#[$attr:meta])* $num:ident = $id:literal => fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty;)*) => {
+            $($(#[$attr])* pub const $num: usize = $id;)*
+        };
+    }
+    syscall_table!(numbers);
+
+    /// Values passed in registers to and from syscalls
+    pub trait Reg {
+        fn from_reg(reg: usize) -> Self;
+        fn to_reg(self) -> usize;
+    }
+
+    impl Reg for usize {
+        fn from_reg(reg: usize) -> Self { reg }
+        fn to_reg(self) -> usize { self }
+    }
+
+    impl Reg for isize {
+        fn from_reg(reg: usize) -> Self { reg as isize }
+        fn to_reg(self) -> usize { self as usize }
+    }
+
+    /// sign-extended, as the riscv calling convention does
+    impl Reg for i32 {
+        fn from_reg(reg: usize) -> Self { reg as i32 }
+        fn to_reg(self) -> usize { self as usize }
+    }
+
+    /// for syscalls returning nothing but success, 0
+    impl Reg for () {
+        fn from_reg(_: usize) -> Self {}
+        fn to_reg(self) -> usize { 0 }
+    }
+
     /// syscall register index
     pub const SYSCALL_REG_NUM: usize = 17; // a7
     pub const SYSCALL_REG_ARG0: usize = 10; // a0
@@ -50,6 +135,29 @@ pub mod syscall {
     pub const SYSCALL_REG_ARG5: usize = 15;
     pub const SYSCALL_REG_ARG6: usize = 16;
     pub const SYSCALL_REG_RET: usize  = 10;
+    /// max exec arguments
+    pub const MAXARG: usize = 32;
+    /// mmap protections
+    pub const PROT_READ: usize = 1;
+    pub const PROT_WRITE: usize = 2;
+    pub const PROT_EXEC: usize = 4;
+    /// mmap flags
+    pub const MAP_SHARED: usize = 0x01;
+    pub const MAP_PRIVATE: usize = 0x02;
+    pub const MAP_FIXED: usize = 0x10;
+    pub const MAP_ANONYMOUS: usize = 0x20;
+    /// open flags
+    pub const O_RDONLY: usize = 0x000;
+    pub const O_WRONLY: usize = 0x001;
+    pub const O_RDWR: usize = 0x002;
+    pub const O_CREAT: usize = 0x040;
+    pub const O_TRUNC: usize = 0x200;
+    /// futex operations
+    pub const FUTEX_WAIT: usize = 0;
+    pub const FUTEX_WAKE: usize = 1;
+    /// range of nice values, lower is higher priority
+    pub const NICE_MIN: i32 = -20;
+    pub const NICE_MAX: i32 = 19;
 }
 
 pub mod layout {
@@ -82,11 +190,17 @@ pub mod layout {
      *              |     FIRMWARE     |
      *  0x00001000  +------------------+ <- riscv_virt_board.mrom
      *
+     *
+     * Devices are mapped into the kernel half of the address space with
+     * the same offset as RAM (i.e., va = pa + PA2VA_OFFSET), so that the
+     * lower half is left entirely to user programs.
+     *
+     * The PLIC and RAM ranges below are those of the QEMU virt board with
+     * `-m 128M`. The kernel uses what the device tree describes instead,
+     * and falls back to them only when there is no device tree.
      */
     pub const PLIC_BASE: usize = 0xc000000;
     pub const PLIC_MMAP_SIZE: usize = 0x600000;
-    pub const PLIC_SENABLE_BASE: usize = PLIC_BASE + 0x2080;
-    pub const PLIC_SPRIORITY_BASE: usize = PLIC_BASE + 0x201000;
 
     pub const MMIO_BASE: usize = 0x10000000;
     pub const MMIO_MMAP_SIZE: usize = 0x8200;
@@ -97,42 +211,66 @@ pub mod layout {
     pub const PHY_SIZE: usize = 128 * 1024 * 1024;
     pub const PHY_STOP: usize = PHY_START + PHY_SIZE;
 
-    pub const KSTACKTOP: usize = PHY_STOP;
     /// Page size 4KB
     pub const PGSIZE: usize = 4 * 1024;
     pub const PGSHIFT: usize = 12;
-    /// Kernel stack size 4KB
-    pub const STACKSIZE: usize = PGSIZE;
-    /// Kernel Heap size 3MB
-    pub const KERNEL_HEAP_SIZE: usize = 0x30_0000;
-
-    #[inline(always)]
-    pub fn plic_pri(intr_src: usize) -> *mut u32 {
-        (PLIC_BASE + intr_src * 4) as *mut u32
-    }
+    /// Kernel stack size 16KB
+    pub const STACKSIZE: usize = 4 * PGSIZE;
+    /// Shift of STACKSIZE, see trap.S
+    pub const STACKSHIFT: usize = 14;
+    /*
+     * Kernel stacks of processes live in the first GiB of the kernel half,
+     * below the linear map. Each takes a slot of 2 * STACKSIZE: the stack is
+     * the upper half, and the lower half is left unmapped as a guard.
+     */
+    pub const KSTACK_BASE: usize = 0xffff_ffc0_0000_0000;
+    pub const KSTACK_REGION: usize = 1 << 30;
+    pub const KSTACK_SLOT: usize = 2 * STACKSIZE;
+    /// Maximum number of harts, hart ids must be below it
+    pub const NCPU: usize = 8;
+    /// Boot stack size of each hart 64KB, see start.S
+    pub const BOOT_STACK_SIZE: usize = 16 * PGSIZE;
 
-    #[inline(always)]
-    pub fn plic_sen(hartid: usize) -> *mut u32 {
-        (PLIC_SENABLE_BASE + hartid * 0x100) as *mut u32
-    }
+    /// Top of the user address space (end of the Sv39 lower half)
+    pub const USER_TOP: usize = 1 << 38;
+    /// User stack grows down from here
+    pub const USTACKTOP: usize = USER_TOP;
+    /// User stack size 16KB
+    pub const USTACKSIZE: usize = 4 * PGSIZE;
+    /// mmap regions are placed top-down from here, the heap grows up to it
+    pub const MMAP_TOP: usize = USTACKTOP - USTACKSIZE;
 
-    #[inline(always)]
-    pub fn plic_spri(hartid: usize) -> *mut u32 {
-        (PLIC_SPRIORITY_BASE + hartid * 0x2000) as *mut u32
-    }
 }
 
+/// PLIC registers, as offsets from the base found in the device tree
 pub mod plic {
-    use crate::layout::*;
+    use crate::vm::PA2VA_OFFSET;
 
-    pub const PLIC_SCLAIM_BASE: usize = PLIC_BASE + 0x201004;
+    pub const PLIC_SENABLE: usize = 0x2080;
+    pub const PLIC_SPRIORITY: usize = 0x201000;
+    pub const PLIC_SCLAIM: usize = 0x201004;
 
     pub const PLIC_VIRTIO0: usize = 1;
     pub const PLIC_UART0: usize = 10;
 
     #[inline(always)]
-    pub fn plic_sclaim(hartid: usize) -> *mut u32 {
-        (PLIC_SCLAIM_BASE + hartid * 0x2000) as *mut u32
+    pub fn plic_pri(base: usize, intr_src: usize) -> *mut u32 {
+        (base + intr_src * 4 + PA2VA_OFFSET) as *mut u32
+    }
+
+    #[inline(always)]
+    pub fn plic_sen(base: usize, hartid: usize) -> *mut u32 {
+        (base + PLIC_SENABLE + hartid * 0x100 + PA2VA_OFFSET) as *mut u32
+    }
+
+    #[inline(always)]
+    pub fn plic_spri(base: usize, hartid: usize) -> *mut u32 {
+        (base + PLIC_SPRIORITY + hartid * 0x2000 + PA2VA_OFFSET) as *mut u32
+    }
+
+    #[inline(always)]
+    pub fn plic_sclaim(base: usize, hartid: usize) -> *mut u32 {
+        (base + PLIC_SCLAIM + hartid * 0x2000 + PA2VA_OFFSET) as *mut u32
     }
 }
 pub mod vm {
@@ -146,6 +284,10 @@ pub mod vm {
     pub const PTE_G: usize = 1 << 5;
     pub const PTE_A: usize = 1 << 6;
     pub const PTE_D: usize = 1 << 7;
+    /// Copy-on-write page (one of the RSW bits reserved for software)
+    pub const PTE_COW: usize = 1 << 8;
+    /// Page of a shared mapping, kept shared and writable across fork
+    pub const PTE_SHARED: usize = 1 << 9;
 
     pub const PTE_SHIFT: usize = 10;
 
@@ -165,6 +307,173 @@ pub mod vm {
     pub fn page_down(addr: usize) -> usize {
         addr & !(PGSIZE - 1)
     }
+
+    #[inline(always)]
+    pub fn page_up(addr: usize) -> usize {
+        (addr + PGSIZE - 1) & !(PGSIZE - 1)
+    }
+}
+
+/// Errors of syscalls, as on Linux
+pub mod errno {
+    /// Why a syscall failed. It returns the number negated in a0,
+    /// see `Errno::to_ret` and `Errno::from_ret`.
+    #[repr(usize)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Errno {
+        /// operation not permitted
+        EPERM = 1,
+        /// no such file or directory
+        ENOENT = 2,
+        /// no such process
+        ESRCH = 3,
+        /// interrupted by a signal
+        EINTR = 4,
+        /// argument list too long
+        E2BIG = 7,
+        /// not an executable
+        ENOEXEC = 8,
+        /// bad file descriptor
+        EBADF = 9,
+        /// no child to wait for
+        ECHILD = 10,
+        /// try again
+        EAGAIN = 11,
+        /// out of memory
+        ENOMEM = 12,
+        /// permission denied
+        EACCES = 13,
+        /// bad address
+        EFAULT = 14,
+        /// the file can't do this, like mapping a console
+        ENODEV = 19,
+        /// not a directory
+        ENOTDIR = 20,
+        /// is a directory
+        EISDIR = 21,
+        /// invalid argument
+        EINVAL = 22,
+        /// too many open files
+        EMFILE = 24,
+        /// no space left on the disk
+        ENOSPC = 28,
+        /// the read end of a pipe is closed
+        EPIPE = 32,
+        /// result too large, like a path for its buffer
+        ERANGE = 34,
+        /// file name too long
+        ENAMETOOLONG = 36,
+        /// no such syscall
+        ENOSYS = 38,
+    }
+
+    impl Errno {
+        /// Every error, in number order
+        pub const ALL: [Errno; 22] = [
+            Errno::EPERM,
+            Errno::ENOENT,
+            Errno::ESRCH,
+            Errno::EINTR,
+            Errno::E2BIG,
+            Errno::ENOEXEC,
+            Errno::EBADF,
+            Errno::ECHILD,
+            Errno::EAGAIN,
+            Errno::ENOMEM,
+            Errno::EACCES,
+            Errno::EFAULT,
+            Errno::ENODEV,
+            Errno::ENOTDIR,
+            Errno::EISDIR,
+            Errno::EINVAL,
+            Errno::EMFILE,
+            Errno::ENOSPC,
+            Errno::EPIPE,
+            Errno::ERANGE,
+            Errno::ENAMETOOLONG,
+            Errno::ENOSYS,
+        ];
+
+        /// The value in a0 of a syscall failing with this error
+        pub const fn to_ret(self) -> usize {
+            (self as usize).wrapping_neg()
+        }
+
+        /// The error of a syscall returning ret, None if it succeeded.
+        /// Unknown errors are EINVAL.
+        pub fn from_ret(ret: usize) -> Option<Self> {
+            // as on Linux, the last 4095 values are errors
+            let errno = ret.wrapping_neg();
+            if errno == 0 || errno >= 4096 {
+                return None;
+            }
+            let known = Self::ALL.iter().find(|&&e| e as usize == errno);
+            Some(known.copied().unwrap_or(Errno::EINVAL))
+        }
+
+        /// A short description, as strerror does
+        pub fn as_str(self) -> &'static str {
+            match self {
+                Errno::EPERM => "operation not permitted",
+                Errno::ENOENT => "no such file or directory",
+                Errno::ESRCH => "no such process",
+                Errno::EINTR => "interrupted",
+                Errno::E2BIG => "argument list too long",
+                Errno::ENOEXEC => "not an executable",
+                Errno::EBADF => "bad file descriptor",
+                Errno::ECHILD => "no child processes",
+                Errno::EAGAIN => "try again",
+                Errno::ENOMEM => "out of memory",
+                Errno::EACCES => "permission denied",
+                Errno::EFAULT => "bad address",
+                Errno::ENODEV => "no such device",
+                Errno::ENOTDIR => "not a directory",
+                Errno::EISDIR => "is a directory",
+                Errno::EINVAL => "invalid argument",
+                Errno::EMFILE => "too many open files",
+                Errno::ENOSPC => "no space left on device",
+                Errno::EPIPE => "broken pipe",
+                Errno::ERANGE => "result too large",
+                Errno::ENAMETOOLONG => "file name too long",
+                Errno::ENOSYS => "function not implemented",
+            }
+        }
+    }
+
+    impl core::fmt::Display for Errno {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            f.write_str(self.as_str())
+        }
+    }
+}
+
+/// Signal numbers, as on Linux
+pub mod signal {
+    /// number of signals, valid ones are 1..NSIG
+    pub const NSIG: usize = 32;
+    pub const SIGHUP: usize = 1;
+    pub const SIGINT: usize = 2;
+    pub const SIGQUIT: usize = 3;
+    pub const SIGILL: usize = 4;
+    pub const SIGABRT: usize = 6;
+    pub const SIGKILL: usize = 9;
+    pub const SIGUSR1: usize = 10;
+    pub const SIGSEGV: usize = 11;
+    pub const SIGUSR2: usize = 12;
+    pub const SIGPIPE: usize = 13;
+    pub const SIGALRM: usize = 14;
+    pub const SIGTERM: usize = 15;
+    pub const SIGCHLD: usize = 17;
+    pub const SIGCONT: usize = 18;
+    pub const SIGSTOP: usize = 19;
+    pub const SIGTSTP: usize = 20;
+    pub const SIGTTIN: usize = 21;
+    pub const SIGTTOU: usize = 22;
+    pub const SIGURG: usize = 23;
+    pub const SIGWINCH: usize = 28;
+    /// handlers with a special meaning
+    pub const SIG_DFL: usize = 0;
+    pub const SIG_IGN: usize = 1;
 }
 
 /// Standard input/output/error settings
@@ -190,10 +499,20 @@ pub mod fs {
     pub const LOGSIZE: u32 = MAXOPBLOCKS * 3;
     /// size of disk block cache
     pub const NBUF: u32 = MAXOPBLOCKS * 3;
+    /// open files per process
+    pub const NOFILE: usize = 16;
+    /// bytes buffered in a pipe
+    pub const PIPESIZE: usize = 512;
     /// maximum file path name
     pub const DIRSIZ: usize = 14;
     pub const NBITMAP: u32 = FSSIZE / BSIZE as u32 + 1;
     pub const NDIRECT: usize = 12;
+    /// number of block addresses in the indirect block
+    pub const NINDIRECT: usize = BSIZE / core::mem::size_of::<u32>();
+    /// max file size in blocks
+    pub const MAXFILE: usize = NDIRECT + NINDIRECT;
+    /// maximum length of a path, including the trailing '\0'
+    pub const MAXPATH: usize = 128;
     /// magic number for file system super block
     pub const FS_MAGIC: u32 = 0x10203040;
     /// BootBlock number
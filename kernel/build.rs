@@ -0,0 +1,36 @@
+//! Embed the function symbols of System.map, written by `make build` after
+//! the previous link, for backtraces, see src/backtrace.rs. The kernel is
+//! linked again with them: they go last in the image, so the code keeps
+//! its addresses, which `make` checks after the second link. Without a
+//! System.map the table is empty.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let map = Path::new("../System.map");
+    println!("cargo:rerun-if-changed={}", map.display());
+    let text = fs::read_to_string(map).unwrap_or_default();
+    // "address type name" lines of code symbols, sorted by address
+    let mut symbols: Vec<(u64, &str)> = text
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let kind = fields.next()?;
+            let name = fields.next()?;
+            let code = matches!(kind, "t" | "T" | "w" | "W");
+            // skip the mapping and local labels of the assembler
+            (code && !name.starts_with(['$', '.'])).then_some((addr, name))
+        })
+        .collect();
+    symbols.sort_unstable();
+    symbols.dedup_by_key(|(addr, _)| *addr);
+    let table: String = symbols
+        .iter()
+        .map(|(addr, name)| format!("{:016x} {}\n", addr, name))
+        .collect();
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("System.map");
+    fs::write(out, table).unwrap();
+}
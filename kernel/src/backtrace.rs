@@ -0,0 +1,142 @@
+//! Backtraces of the kernel for panics. The kernel is built with frame
+//! pointers, so the call chain is followed through the saved s0 of each
+//! frame, and return addresses are named with the symbol table embedded
+//! in the image by build.rs, "address name" lines sorted by address.
+
+use core::arch::asm;
+use core::fmt;
+
+use config::layout::*;
+
+/// Frames printed at most, in case the chain loops
+const MAX_FRAMES: usize = 64;
+
+#[link_section = ".ksyms"]
+#[used]
+static SYSTEM_MAP: [u8; include_bytes!(concat!(env!("OUT_DIR"), "/System.map")).len()] =
+    *include_bytes!(concat!(env!("OUT_DIR"), "/System.map"));
+
+/// The embedded table, found through the linker script so that code does
+/// not depend on its size and keeps its addresses when it changes.
+fn system_map() -> &'static str {
+    extern "C" {
+        fn sksyms();
+        fn eksyms();
+    }
+    let start = sksyms as *const () as usize;
+    let len = eksyms as *const () as usize - start;
+    let bytes = unsafe { core::slice::from_raw_parts(start as *const u8, len) };
+    core::str::from_utf8(bytes).unwrap_or("")
+}
+
+/// The symbol of map containing addr and the offset of addr in it:
+/// the last one at or below addr.
+pub fn lookup(map: &str, addr: usize) -> Option<(&str, usize)> {
+    let mut found = None;
+    for line in map.lines() {
+        let Some((start, name)) = line.split_once(' ') else {
+            continue;
+        };
+        let Ok(start) = usize::from_str_radix(start, 16) else {
+            continue;
+        };
+        if start > addr {
+            break;
+        }
+        found = Some((name, addr - start));
+    }
+    found
+}
+
+/// The function of the kernel containing addr and the offset of addr in it.
+/// None outside of the kernel code, or if the table is missing or comes
+/// from another build: `walk` must then be found where it is.
+pub fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    extern "C" {
+        fn stext();
+        fn etext();
+    }
+    let map = system_map();
+    let walk = walk as *const () as usize;
+    match lookup(map, walk) {
+        Some((name, 0)) if name.contains("backtrace::walk") => {}
+        _ => return None,
+    }
+    if !(stext as *const () as usize..etext as *const () as usize).contains(&addr) {
+        return None;
+    }
+    lookup(map, addr)
+}
+
+/// Whether [lo, hi) is within one of the kernel stacks: the stack of a
+/// process, the boot stack or the emergency stack of a hart.
+fn on_stack(lo: usize, hi: usize) -> bool {
+    extern "C" {
+        fn boot_stack_lower_bound();
+        fn emergency_stack();
+    }
+    let stacks = [
+        (
+            boot_stack_lower_bound as *const () as usize,
+            BOOT_STACK_SIZE,
+        ),
+        (emergency_stack as *const () as usize, STACKSIZE),
+    ];
+    if (KSTACK_BASE..KSTACK_BASE + KSTACK_REGION).contains(&lo) {
+        let top = KSTACK_BASE + ((lo - KSTACK_BASE) / KSTACK_SLOT + 1) * KSTACK_SLOT;
+        return lo >= top - STACKSIZE && hi <= top;
+    }
+    stacks.iter().any(|&(base, size)| {
+        lo >= base && hi <= base + NCPU * size && (lo - base) / size == (hi - 1 - base) / size
+    })
+}
+
+/// Call f with the return address of each frame of the caller, innermost
+/// first. A frame pointer points above the return address and the frame
+/// pointer of the caller, saved at fp - 8 and fp - 16. Stop at the first
+/// one that is not on a kernel stack.
+#[inline(never)]
+pub fn walk(f: &mut dyn FnMut(usize)) {
+    let mut fp: usize;
+    unsafe { asm!("mv {}, s0", out(reg) fp) };
+    for _ in 0..MAX_FRAMES {
+        if !fp.is_multiple_of(8) || fp < 16 || !on_stack(fp - 16, fp) {
+            break;
+        }
+        let (ra, prev) = unsafe { (*((fp - 8) as *const usize), *((fp - 16) as *const usize)) };
+        if ra == 0 {
+            break;
+        }
+        f(ra);
+        fp = prev;
+    }
+}
+
+/// An address with its symbol if known, as `0x... <name+0x..>`
+pub struct Symbol(pub usize);
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        if let Some((name, offset)) = symbolize(self.0) {
+            write!(f, " <{}+{:#x}>", name, offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Print the backtrace of the caller on the console.
+pub fn print() {
+    println!("backtrace:");
+    let mut depth = 0;
+    walk(&mut |ra| {
+        // ra - 1 is in the call, which may end its function
+        match symbolize(ra - 1) {
+            Some((name, offset)) => {
+                println!("  #{:<2} {:#018x} {}+{:#x}", depth, ra, name, offset + 1)
+            }
+            None => println!("  #{:<2} {:#018x}", depth, ra),
+        }
+        depth += 1;
+    });
+}
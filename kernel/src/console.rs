@@ -75,11 +75,18 @@ impl Write for DummyOut {
     }
 }
 
+/// Keeps the output of different harts from interleaving
+static PRINT_LOCK: crate::sync::SpinLock<()> = crate::sync::SpinLock::new((), "PrintLock");
+
+pub fn _print(args: core::fmt::Arguments) {
+    let _guard = PRINT_LOCK.lock();
+    DummyOut.write_fmt(args).unwrap();
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ({
-        use core::fmt::Write;
-        $crate::console::DummyOut.write_fmt(format_args!($($arg)*)).unwrap();
+        $crate::console::_print(format_args!($($arg)*));
     });
 }
 
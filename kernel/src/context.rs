@@ -1,5 +1,14 @@
 pub type Reg = usize;
 
+const SSTATUS_SIE: usize = 1 << 1;
+const SSTATUS_SPIE: usize = 1 << 5;
+const SSTATUS_SPP: usize = 1 << 8;
+
+/// Indices of registers in `TrapFrame::regs`, x1, x2 and x10
+pub const REG_RA: usize = 1;
+pub const REG_SP: usize = 2;
+pub const REG_A0: usize = 10;
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 #[rustfmt::skip]
@@ -8,6 +17,8 @@ pub struct TrapFrame {
     pub sstatus:    Reg, 
     pub sepc:       Reg,
     pub scause:     Reg,
+    /// hartid (tp) of the kernel, restored on traps from user mode
+    pub hartid:     Reg,
 }
 
 impl TrapFrame {
@@ -17,8 +28,28 @@ impl TrapFrame {
             sstatus: 0,
             sepc: 0,
             scause: 0,
+            hartid: 0,
         }
     }
+
+    /// Trap frame for the first return to user mode.
+    /// - entry: Address of the first user instruction
+    /// - sp: User stack pointer
+    pub fn user(entry: usize, sp: usize) -> Self {
+        let sstatus: usize;
+        unsafe { core::arch::asm!("csrr {}, sstatus", out(reg) sstatus) };
+        let mut tf = Self::new();
+        tf.regs[REG_SP] = sp;
+        tf.sepc = entry;
+        // sret to user mode with interrupts enabled
+        tf.sstatus = (sstatus & !(SSTATUS_SPP | SSTATUS_SIE)) | SSTATUS_SPIE;
+        tf
+    }
+
+    /// Whether the trap was taken from user mode.
+    pub fn from_user(&self) -> bool {
+        self.sstatus & SSTATUS_SPP == 0
+    }
 }
 
 impl Default for TrapFrame {
@@ -50,7 +81,7 @@ pub struct Context {
 }
 
 impl Context {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             ra: 0,
             sp: 0,
@@ -0,0 +1,80 @@
+//! Per-hart state and the boot of the secondary harts.
+
+use config::layout::NCPU;
+use config::vm::PA2VA_OFFSET;
+use fdt::Fdt;
+
+use crate::context::Context;
+
+/// State of a hart, only touched by the hart itself with interrupts off
+#[rustfmt::skip]
+pub struct Cpu {
+    /// pid of the process running on this hart
+    pub proc:       Option<usize>,
+    /// context of the scheduler loop, see `sched::scheduler`
+    pub scheduler:  Context,
+    /// depth of spin locks held, see `sync::push_off`
+    pub noff:       usize,
+    /// whether interrupts were enabled before the first lock
+    pub intena:     bool,
+    /// time of the next scheduler tick
+    pub next_tick:  usize,
+}
+
+impl Cpu {
+    const fn new() -> Self {
+        Self {
+            proc: None,
+            scheduler: Context::new(),
+            noff: 0,
+            intena: false,
+            next_tick: 0,
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const CPU: Cpu = Cpu::new();
+static mut CPUS: [Cpu; NCPU] = [CPU; NCPU];
+
+/// The state of the current hart.
+/// Interrupts must be off, or the process could move to another hart.
+pub fn mycpu() -> &'static mut Cpu {
+    let id = cpuid!();
+    assert!(id < NCPU, "hartid {} out of range", id);
+    unsafe { &mut *core::ptr::addr_of_mut!(CPUS[id]) }
+}
+
+/// Find the harts in the `/cpus` node of the device tree, and start all of
+/// them but the boot hart at `_start_secondary`.
+pub fn init(hartid: usize, dtb_pa: usize) {
+    extern "C" {
+        fn _start_secondary();
+    }
+    let fdt = unsafe { Fdt::from_ptr((dtb_pa + PA2VA_OFFSET) as *const u8).unwrap() };
+    for cpu in fdt.cpus() {
+        let id = cpu.ids().first();
+        if id == hartid {
+            continue;
+        }
+        if id >= NCPU {
+            warn!("hart {} ignored, NCPU is {}", id, NCPU);
+            continue;
+        }
+        // the hart starts with paging off
+        let entry = _start_secondary as *const () as usize - PA2VA_OFFSET;
+        let err = crate::sbi::hart_start(id, entry, 0);
+        if err != 0 {
+            error!("Failed to start hart {}: SBI error {}", id, err as isize);
+        }
+    }
+}
+
+/// Rust entry of the secondary harts, see start.S
+#[no_mangle]
+pub extern "C" fn secondary_main(hartid: usize) -> ! {
+    crate::mm::vm::use_kernel_pagetable();
+    crate::trap::init(hartid);
+    info!("Hart {} started", hartid);
+    crate::sched::scheduler()
+}
@@ -0,0 +1,215 @@
+//! Memory layout of the machine, read from the flattened device tree:
+//! RAM from `/memory`, the PLIC from `/soc/plic`, and the ranges firmware
+//! keeps for itself from `/reserved-memory` and the reservation block.
+//! This runs before the heap exists, so nothing here allocates.
+
+use config::{layout::*, vm::*};
+use fdt::Fdt;
+
+use crate::sync::SpinLock;
+
+/// Most ranges kept in a `Regions`
+pub const MAX_REGIONS: usize = 16;
+
+/// Sorted and disjoint physical address ranges [start, end)
+#[derive(Clone, Copy, Default)]
+pub struct Regions {
+    list: [(usize, usize); MAX_REGIONS],
+    len: usize,
+}
+
+impl Regions {
+    pub const fn new() -> Self {
+        Self {
+            list: [(0, 0); MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.list[..self.len].iter().copied()
+    }
+
+    /// Add [start, end), merging it with the ranges it touches.
+    /// Ranges beyond MAX_REGIONS are dropped with a warning.
+    pub fn add(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        let (mut start, mut end) = (start, end);
+        let mut kept = Self::new();
+        for (s, e) in self.iter() {
+            if e < start || s > end {
+                kept.push(s, e);
+            } else {
+                start = start.min(s);
+                end = end.max(e);
+            }
+        }
+        kept.push(start, end);
+        kept.list[..kept.len].sort_unstable();
+        *self = kept;
+    }
+
+    /// Take [start, end) out of the ranges, splitting those it falls into.
+    pub fn remove(&mut self, start: usize, end: usize) {
+        let mut kept = Self::new();
+        for (s, e) in self.iter() {
+            kept.push(s, e.min(start));
+            kept.push(s.max(end), e);
+        }
+        *self = kept;
+    }
+
+    /// Take size bytes from the start of the first range large enough.
+    pub fn take(&mut self, size: usize) -> Option<usize> {
+        let i = self.iter().position(|(s, e)| e - s >= size)?;
+        let start = self.list[i].0;
+        self.remove(start, start + size);
+        Some(start)
+    }
+
+    fn push(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        if self.len == MAX_REGIONS {
+            warn!("too many memory ranges, [{:#x}, {:#x}) ignored", start, end);
+            return;
+        }
+        self.list[self.len] = (start, end);
+        self.len += 1;
+    }
+}
+
+/// What the kernel needs to know about the machine before setting up memory
+#[derive(Clone, Copy)]
+pub struct Machine {
+    /// RAM
+    pub memory: Regions,
+    /// RAM that belongs to firmware, neither mapped nor allocated.
+    /// The firmware below the kernel image is always in it.
+    pub reserved: Regions,
+    /// the device tree blob itself, mapped but not allocated
+    pub dtb: (usize, usize),
+    /// registers of the PLIC
+    pub plic: (usize, usize),
+    /// the kernel command line, from `/chosen/bootargs`
+    pub bootargs: &'static str,
+}
+
+impl Machine {
+    /// The QEMU virt board with 128 MiB of RAM, see `config::layout`
+    const fn default_board() -> Self {
+        let mut memory = Regions::new();
+        memory.list[0] = (PHY_START, PHY_STOP);
+        memory.len = 1;
+        let mut reserved = Regions::new();
+        reserved.list[0] = (PHY_START, KERNEL_BASE);
+        reserved.len = 1;
+        Self {
+            memory,
+            reserved,
+            dtb: (0, 0),
+            plic: (PLIC_BASE, PLIC_BASE + PLIC_MMAP_SIZE),
+            bootargs: "",
+        }
+    }
+
+    /// Physical range of the kernel image, from stext to ekernel
+    pub fn kernel_image() -> (usize, usize) {
+        extern "C" {
+            fn stext();
+            fn ekernel();
+        }
+        (stext as *const () as usize - PA2VA_OFFSET, ekernel as *const () as usize - PA2VA_OFFSET)
+    }
+
+    /// RAM the kernel maps in its linear map: all of it but the reserved
+    /// ranges and the kernel image, which is mapped section by section.
+    pub fn mapped_memory(&self) -> Regions {
+        let mut regions = self.memory;
+        for (start, end) in self.reserved.iter() {
+            regions.remove(start, end);
+        }
+        let (start, end) = Self::kernel_image();
+        regions.remove(start, end);
+        regions
+    }
+
+    /// Whether the word option is on the kernel command line
+    pub fn has_option(&self, option: &str) -> bool {
+        self.bootargs.split_whitespace().any(|arg| arg == option)
+    }
+
+    /// RAM left for the frame allocator
+    pub fn free_memory(&self) -> Regions {
+        let mut regions = self.mapped_memory();
+        regions.remove(page_down(self.dtb.0), page_up(self.dtb.1));
+        regions
+    }
+}
+
+static MACHINE: SpinLock<Machine> = SpinLock::new(Machine::default_board(), "MachineLock");
+
+/// The layout read by `init`, or the default board if there was no device tree.
+pub fn machine() -> Machine {
+    *MACHINE.lock()
+}
+
+/// Read the memory layout from the device tree at dtb_pa.
+/// Paging is still set up by start.S, which maps the first GiB of RAM,
+/// where firmware puts the device tree.
+pub fn init(dtb_pa: usize) {
+    let fdt = unsafe { Fdt::from_ptr((dtb_pa + PA2VA_OFFSET) as *const u8).unwrap() };
+    let mut machine = Machine::default_board();
+    machine.dtb = (dtb_pa, dtb_pa + fdt.total_size());
+
+    if let Some(node) = fdt.find_node("/memory") {
+        machine.memory = Regions::new();
+        for region in node.reg().into_iter().flatten() {
+            let start = region.starting_address as usize;
+            machine.memory.add(start, start + region.size.unwrap_or(0));
+        }
+    }
+
+    let plic = fdt
+        .find_node("/soc/plic")
+        .or_else(|| fdt.find_compatible(&["riscv,plic0", "sifive,plic-1.0.0"]));
+    if let Some(reg) = plic.and_then(|node| node.reg()).and_then(|mut reg| reg.next()) {
+        let start = reg.starting_address as usize;
+        machine.plic = (start, start + reg.size.unwrap_or(PLIC_MMAP_SIZE));
+    }
+
+    for resv in fdt.memory_reservations() {
+        let start = resv.address() as usize;
+        machine.reserved.add(page_down(start), page_up(start + resv.size()));
+    }
+    if let Some(node) = fdt.find_node("/reserved-memory") {
+        for child in node.children() {
+            for region in child.reg().into_iter().flatten() {
+                let start = region.starting_address as usize;
+                let end = start + region.size.unwrap_or(0);
+                machine.reserved.add(page_down(start), page_up(end));
+            }
+        }
+    }
+
+    // the device tree stays mapped, so the string can be kept
+    let bootargs = fdt
+        .find_node("/chosen")
+        .and_then(|node| node.property("bootargs"));
+    machine.bootargs = bootargs.and_then(|prop| prop.as_str()).unwrap_or("");
+
+    for (start, end) in machine.memory.iter() {
+        info!("RAM [{:#x}, {:#x})", start, end);
+    }
+    for (start, end) in machine.reserved.iter() {
+        info!("Reserved [{:#x}, {:#x})", start, end);
+    }
+    info!("PLIC [{:#x}, {:#x})", machine.plic.0, machine.plic.1);
+    if !machine.bootargs.is_empty() {
+        info!("Command line: {}", machine.bootargs);
+    }
+    *MACHINE.lock() = machine;
+}
@@ -191,8 +191,9 @@ impl Dir for Block {
             if entry.inum == 0 {
                 return None;
             }
+            // names shorter than DIRSIZ are padded with '\0'
             let name = unsafe { core::str::from_utf8_unchecked(&entry.name) };
-            if path == name {
+            if path == name.trim_end_matches('\0') {
                 return Inode::get(entry.inum);
             }
             offset += core::mem::size_of::<DirEntry>();
@@ -217,10 +218,12 @@ impl Dir for Block {
             }
             offset += core::mem::size_of::<DirEntry>();
         }
+        // the directory inode grows by the entry, see `Inode::dirlink`
+        if size + core::mem::size_of::<DirEntry>() > BSIZE {
+            return None;
+        }
         let dir = DirEntry::new(inum, name);
         write_as(self, size, dir);
-        let new_size = size + core::mem::size_of::<DirEntry>();
-        write_as(self, 0, new_size as u32);
         Some(())
     }
 }
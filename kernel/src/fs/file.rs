@@ -1,23 +1,153 @@
+use alloc::sync::Arc;
+use config::errno::Errno;
+use config::syscall::*;
+
 use super::nameiparent;
-use super::Inode;
+use super::{FType, Inode, Pipe};
+use crate::sync::Mutex;
+
+/// An open file, shared by the descriptors duplicated from it
+pub type FileRef = Arc<Mutex<File>>;
 
 /// File is a wrapper around of inode, device, pipe or None
 pub enum File {
-    Inode { inode: Inode, off: usize },
+    Inode {
+        inode: Inode,
+        off: usize,
+        readable: bool,
+        writable: bool,
+    },
+    /// the terminal, stdin and stdout of init. Readers don't hold the
+    /// file lock while they wait, see `read_console`
+    Console,
+    /// one end of a pipe, closed when the file is dropped
+    Pipe {
+        pipe: Arc<Pipe>,
+        writable: bool,
+    },
     None,
 }
 
 impl File {
-    pub fn open(path: &str, _omode: u32) -> Option<Self> {
-        let (mut pinode, name) = nameiparent(path)?;
-        match pinode.dirlookup(name) {
-            Some(inode) => Some(Self::Inode { inode, off: 0 }),
-            None => {
-                // create a new file
-                let inode = Inode::new(super::FType::File, 0, 0);
-                pinode.dirlink(name, inode.inum)?;
-                Some(Self::Inode { inode, off: 0 })
+    /// Open the file at path with flags O_*, creating it with O_CREAT.
+    /// Fail with ENOENT if it doesn't exist, or EISDIR for a directory
+    /// opened for writing.
+    pub fn open(path: &str, flags: usize) -> Result<Self, Errno> {
+        let (mut pinode, name) = nameiparent(path).ok_or(Errno::ENOENT)?;
+        let mut inode = match pinode.dirlookup(name) {
+            Some(inode) => inode,
+            None if flags & O_CREAT != 0 => {
+                let mut inode = Inode::new(FType::File, 0, 0).ok_or(Errno::ENOSPC)?;
+                if pinode.dirlink(name, inode.inum).is_none() {
+                    inode.free();
+                    return Err(Errno::ENOSPC);
+                }
+                inode
+            }
+            None => return Err(Errno::ENOENT),
+        };
+        let readable = flags & O_WRONLY == 0;
+        let writable = flags & (O_WRONLY | O_RDWR) != 0;
+        if inode.dinode.typ == FType::Dir && writable {
+            return Err(Errno::EISDIR);
+        }
+        if flags & O_TRUNC != 0 && writable {
+            inode.truncate();
+        }
+        Ok(Self::Inode {
+            inode,
+            off: 0,
+            readable,
+            writable,
+        })
+    }
+
+    /// Read into dst from the current offset and advance it.
+    /// Console reads wait for a line and stop at its end.
+    /// Return the number of bytes read. Fail with EBADF if the file is not
+    /// readable, or EINTR if a signal interrupts the wait before anything is read.
+    pub fn read(&mut self, dst: &mut [u8]) -> Result<usize, Errno> {
+        match self {
+            Self::Inode {
+                inode,
+                off,
+                readable: true,
+                ..
+            } => {
+                let n = inode.read(*off, dst);
+                *off += n;
+                Ok(n)
+            }
+            Self::Console => Self::read_console(dst),
+            Self::Pipe {
+                pipe,
+                writable: false,
+            } => pipe.read(dst),
+            _ => Err(Errno::EBADF),
+        }
+    }
+
+    /// Read a line from the console into dst, see `read`. The console has
+    /// no state of its own, so this needs no `File`.
+    pub fn read_console(dst: &mut [u8]) -> Result<usize, Errno> {
+        let mut cnt = 0;
+        while cnt < dst.len() {
+            match crate::io::getchar() {
+                None if cnt > 0 => break,
+                None => return Err(Errno::EINTR),
+                Some('\r' | '\n') => {
+                    if cnt > 0 {
+                        break;
+                    }
+                }
+                Some(ch) => {
+                    dst[cnt] = ch as u8;
+                    cnt += 1;
+                }
+            }
+        }
+        Ok(cnt)
+    }
+
+    /// Write src at the current offset and advance it.
+    /// Return the number of bytes written, or fail with EBADF if the file
+    /// is not writable.
+    pub fn write(&mut self, src: &[u8]) -> Result<usize, Errno> {
+        match self {
+            Self::Inode {
+                inode,
+                off,
+                writable: true,
+                ..
+            } => {
+                let n = inode.write(*off, src);
+                *off += n;
+                Ok(n)
             }
+            Self::Console => {
+                use core::fmt::Write;
+                // invalid UTF-8 is shown as U+FFFD
+                for chunk in src.utf8_chunks() {
+                    let _ = crate::io::Stdout.write_str(chunk.valid());
+                    if !chunk.invalid().is_empty() {
+                        let _ = crate::io::Stdout.write_char(char::REPLACEMENT_CHARACTER);
+                    }
+                }
+                Ok(src.len())
+            }
+            Self::Pipe {
+                pipe,
+                writable: true,
+            } => pipe.write(src),
+            _ => Err(Errno::EBADF),
+        }
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        if let Self::Pipe { pipe, writable } = self {
+            pipe.close(*writable);
         }
     }
 }
@@ -1,7 +1,7 @@
 //! Inode layer of file system.
 
 use super::{
-    block::{BitMap, Dir},
+    block::{BitMap, Dir, DirEntry},
     read_as, write_as, Block,
 };
 use config::fs::*;
@@ -36,7 +36,8 @@ impl Inode {
     }
 
     /// Allocate a new inode with the given type and major/minor number.
-    pub fn new(typ: FType, major: u16, minor: u16) -> Self {
+    /// Return None if there is no free inode left.
+    pub fn new(typ: FType, major: u16, minor: u16) -> Option<Self> {
         let dinode = DInode {
             typ,
             major,
@@ -47,7 +48,7 @@ impl Inode {
         };
         // Allocate a new inode in bitmap
         let mut bitmap = Block::read_block(INODE_BITMAP_START);
-        let inum = bitmap.alloc().expect("Inode::new: no inodes");
+        let inum = bitmap.alloc()?;
         // Write the new inode to disk
         let inode = Self {
             dinode,
@@ -56,7 +57,7 @@ impl Inode {
             refcnt: 1,
         };
         inode.write_back();
-        inode
+        Some(inode)
     }
 
     /// Copy a modified in-memory inode to disk.
@@ -96,29 +97,158 @@ impl Inode {
     /// Write an inode to disk
     pub fn write_back(&self) {
         let (block_num, offset) = Self::calcu_addr(self.inum);
-        // read the block containing the inode
-        let mut block = Block::new(block_num);
+        // read the block containing the inode, it holds others too
+        let mut block = Block::read_block(block_num);
         // write the inode to the buffer
         write_as(&mut block, offset, self.dinode);
     }
 
-    /// look for a directory entry in a directory inode
+    /// Return the disk block address of the nth block in the inode.
+    fn bmap(&self, bn: usize) -> usize {
+        if bn < NDIRECT {
+            return self.dinode.addrs[bn] as usize;
+        }
+        let bn = bn - NDIRECT;
+        assert!(bn < NINDIRECT, "bmap: out of range");
+        let indirect = Block::read_block(self.dinode.addrs[NDIRECT] as usize);
+        read_as::<u32>(&indirect, bn * core::mem::size_of::<u32>()) as usize
+    }
+
+    /// Like `bmap`, allocating the block if it is not there yet.
+    /// Return None when the disk is full.
+    fn bmap_alloc(&mut self, bn: usize) -> Option<usize> {
+        if bn < NDIRECT {
+            if self.dinode.addrs[bn] == 0 {
+                self.dinode.addrs[bn] = balloc()?;
+            }
+            return Some(self.dinode.addrs[bn] as usize);
+        }
+        let bn = bn - NDIRECT;
+        if bn >= NINDIRECT {
+            return None;
+        }
+        if self.dinode.addrs[NDIRECT] == 0 {
+            self.dinode.addrs[NDIRECT] = balloc()?;
+        }
+        let mut indirect = Block::read_block(self.dinode.addrs[NDIRECT] as usize);
+        let off = bn * core::mem::size_of::<u32>();
+        let mut addr = read_as::<u32>(&indirect, off);
+        if addr == 0 {
+            addr = balloc()?;
+            write_as(&mut indirect, off, addr);
+        }
+        Some(addr as usize)
+    }
+
+    /// Read data from the inode, starting at byte offset off.
+    /// Return the number of bytes read.
+    pub fn read(&self, off: usize, dst: &mut [u8]) -> usize {
+        let size = self.dinode.size as usize;
+        if off >= size {
+            return 0;
+        }
+        let n = dst.len().min(size - off);
+        let mut tot = 0;
+        while tot < n {
+            let pos = off + tot;
+            let block = Block::read_block(self.bmap(pos / BSIZE));
+            let start = pos % BSIZE;
+            let m = (n - tot).min(BSIZE - start);
+            dst[tot..tot + m].copy_from_slice(&block.data[start..start + m]);
+            tot += m;
+        }
+        n
+    }
+
+    /// Write src to the inode, starting at byte offset off, and grow it
+    /// if needed. Return the number of bytes written, which is short when
+    /// the disk is full or the file reaches MAXFILE blocks.
+    pub fn write(&mut self, off: usize, src: &[u8]) -> usize {
+        if off > self.dinode.size as usize {
+            return 0;
+        }
+        let mut tot = 0;
+        while tot < src.len() {
+            let pos = off + tot;
+            let Some(bn) = self.bmap_alloc(pos / BSIZE) else {
+                break;
+            };
+            let mut block = Block::read_block(bn);
+            let start = pos % BSIZE;
+            let m = (src.len() - tot).min(BSIZE - start);
+            block.data[start..start + m].copy_from_slice(&src[tot..tot + m]);
+            tot += m;
+        }
+        self.dinode.size = self.dinode.size.max((off + tot) as u32);
+        // the block addresses may have changed too
+        self.write_back();
+        tot
+    }
+
+    /// Free the data blocks of the inode and make it empty.
+    pub fn truncate(&mut self) {
+        for i in 0..NDIRECT {
+            if self.dinode.addrs[i] != 0 {
+                bfree(self.dinode.addrs[i]);
+                self.dinode.addrs[i] = 0;
+            }
+        }
+        if self.dinode.addrs[NDIRECT] != 0 {
+            let indirect = Block::read_block(self.dinode.addrs[NDIRECT] as usize);
+            for i in 0..NINDIRECT {
+                let addr = read_as::<u32>(&indirect, i * core::mem::size_of::<u32>());
+                if addr != 0 {
+                    bfree(addr);
+                }
+            }
+            bfree(self.dinode.addrs[NDIRECT]);
+            self.dinode.addrs[NDIRECT] = 0;
+        }
+        self.dinode.size = 0;
+        self.write_back();
+    }
+
+    /// look for a directory entry in a directory inode,
+    /// None if it is not there or this is not a directory
     pub fn dirlookup(&self, name: &str) -> Option<Inode> {
         if self.dinode.typ != FType::Dir {
-            panic!("dirlookup not DIR");
+            return None;
         }
         let block = Block::read_block(self.dinode.addrs[0] as usize);
         block.dirlookup(name, self.dinode.size as usize)
     }
 
-    /// Write a new directory entry (name, inum) into the directory
+    /// Write a new directory entry (name, inum) into the directory.
+    /// Return None if it is full or this is not a directory.
     pub fn dirlink(&mut self, name: &str, inum: u32) -> Option<()> {
         if self.dinode.typ != FType::Dir {
-            panic!("dirlink not DIR");
+            return None;
         }
         let mut block = Block::read_block(self.dinode.addrs[0] as usize);
-        block.dirlink(name, inum, self.dinode.size as usize)
+        block.dirlink(name, inum, self.dinode.size as usize)?;
+        self.dinode.size += core::mem::size_of::<DirEntry>() as u32;
+        self.write_back();
+        Some(())
+    }
+}
+
+/// Allocate a zeroed disk block, None when the disk is full.
+fn balloc() -> Option<u32> {
+    let mut bitmap = Block::read_block(BLOCK_BITMAP_START);
+    let bn = bitmap.alloc()?;
+    if bn >= FSSIZE {
+        bitmap.set(bn, 0);
+        return None;
     }
+    // written back zeroed when dropped
+    drop(Block::new(bn as usize));
+    Some(bn)
+}
+
+/// Free a disk block.
+fn bfree(bn: u32) {
+    let mut bitmap = Block::read_block(BLOCK_BITMAP_START);
+    bitmap.set(bn, 0);
 }
 
 /// On-disk inode structure
@@ -5,6 +5,8 @@
 //! + Inode: allocator for file system objects. - inode.rs
 //! + Names: paths for convenient naming. - path.rs
 //! + Files: inode allocator, reading, writing, metadata. - file.rs
+//!
+//! Pipes are files too, see pipe.rs.
 
 use crate::sync::SpinLock;
 
@@ -12,9 +14,10 @@ use lazy_static::*;
 
 /* File system interface */
 pub use block::{read_as, write_as, BitMap, Block, Dir, SuperBlock};
-pub use file::File;
+pub use file::{File, FileRef};
 pub use inode::{FType, Inode};
 pub use path::{namei, nameiparent};
+pub use pipe::Pipe;
 
 mod block;
 mod cache;
@@ -22,6 +25,7 @@ mod file;
 mod inode;
 mod log;
 mod path;
+mod pipe;
 
 lazy_static! {
     pub static ref FS: SpinLock<FileSystem> = SpinLock::new(FileSystem::new(), "FileSystemLock");
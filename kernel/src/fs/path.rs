@@ -4,41 +4,32 @@
 use super::inode::{FType, Inode};
 
 /// Look up and return the inode for a path name.
-/// If parent is true, return the inode for the parent and copy the final
-/// path element into name, else return the inode for the last path element.
+/// If parent is true, return the inode for the parent and the final
+/// path element, else return the inode for the last path element.
+/// There are no working directories, relative paths start at the root.
 fn namex(path: &str, nameiparent: bool) -> Option<(Inode, &str)> {
     let mut path = path;
-    let mut name = "";
-    // relative path or absolute path
-    let mut ip = if path.starts_with('/') {
-        Inode::root()
-    } else {
-        todo!("namex: relative path")
-    };
+    let mut ip = Inode::root();
     loop {
-        while path.starts_with('/') {
-            path = &path[1..];
-        }
+        path = path.trim_start_matches('/');
         if path.is_empty() {
             break;
         }
-        let mut next = path;
-        while !next.is_empty() && !next.starts_with('/') {
-            next = &next[1..];
-        }
-        name = &path[..path.len() - next.len()];
-        path = next;
-        let ip_next = ip.dirlookup(name)?;
-        ip = ip_next;
+        let (name, next) = path.split_at(path.find('/').unwrap_or(path.len()));
         if ip.dinode.typ != FType::Dir {
-            break;
+            return None;
+        }
+        if nameiparent && next.trim_start_matches('/').is_empty() {
+            return Some((ip, name));
         }
+        ip = ip.dirlookup(name)?;
+        path = next;
     }
     if nameiparent {
-        Some((ip, name))
-    } else {
-        Some((ip, path))
+        // the root has no parent
+        return None;
     }
+    Some((ip, path))
 }
 
 /// Look up and return the inode for a path name.
@@ -0,0 +1,108 @@
+//! Anonymous pipes: a bounded ring buffer between a read end and a write end.
+
+use config::errno::Errno;
+use config::fs::PIPESIZE;
+
+use crate::sync::{SpinLock, WaitQueue};
+
+pub struct Pipe {
+    buf: SpinLock<PipeBuf>,
+    /// readers waiting for data
+    readers: WaitQueue,
+    /// writers waiting for room
+    writers: WaitQueue,
+}
+
+struct PipeBuf {
+    data: [u8; PIPESIZE],
+    /// bytes read and written so far, the buffer holds nwrite - nread
+    nread: usize,
+    nwrite: usize,
+    /// whether the read and the write end are still open
+    readopen: bool,
+    writeopen: bool,
+}
+
+impl Pipe {
+    pub fn new() -> Self {
+        Self {
+            buf: SpinLock::new(
+                PipeBuf {
+                    data: [0; PIPESIZE],
+                    nread: 0,
+                    nwrite: 0,
+                    readopen: true,
+                    writeopen: true,
+                },
+                "PipeLock",
+            ),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+        }
+    }
+
+    /// Read what is buffered into dst, waiting until there is something.
+    /// Return the number of bytes read, 0 once the write end is closed.
+    /// Fail with EINTR if a signal interrupts the wait.
+    pub fn read(&self, dst: &mut [u8]) -> Result<usize, Errno> {
+        let mut buf = self.buf.lock();
+        while buf.nread == buf.nwrite && buf.writeopen && !dst.is_empty() {
+            if crate::signal::interrupted() {
+                return Err(Errno::EINTR);
+            }
+            buf = self.readers.sleep(buf);
+        }
+        let n = dst.len().min(buf.nwrite - buf.nread);
+        for byte in dst[..n].iter_mut() {
+            *byte = buf.data[buf.nread % PIPESIZE];
+            buf.nread += 1;
+        }
+        drop(buf);
+        self.writers.wake_all();
+        Ok(n)
+    }
+
+    /// Write all of src, waiting for room while the buffer is full.
+    /// Fail with EPIPE if the read end is closed before everything is
+    /// written, or EINTR if a signal interrupts the wait.
+    pub fn write(&self, src: &[u8]) -> Result<usize, Errno> {
+        let mut buf = self.buf.lock();
+        for &byte in src {
+            while buf.nwrite == buf.nread + PIPESIZE && buf.readopen {
+                self.readers.wake_all();
+                if crate::signal::interrupted() {
+                    return Err(Errno::EINTR);
+                }
+                buf = self.writers.sleep(buf);
+            }
+            if !buf.readopen {
+                return Err(Errno::EPIPE);
+            }
+            let i = buf.nwrite % PIPESIZE;
+            buf.data[i] = byte;
+            buf.nwrite += 1;
+        }
+        drop(buf);
+        self.readers.wake_all();
+        Ok(src.len())
+    }
+
+    /// Close one end, waking up whoever waits on the other.
+    pub fn close(&self, writable: bool) {
+        let mut buf = self.buf.lock();
+        if writable {
+            buf.writeopen = false;
+        } else {
+            buf.readopen = false;
+        }
+        drop(buf);
+        self.readers.wake_all();
+        self.writers.wake_all();
+    }
+}
+
+impl Default for Pipe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,85 @@
+//! Futexes: user threads sleep on a word of their memory until another
+//! thread changes it and wakes them up, see ulib's `Mutex`.
+//! Waiters are keyed by the address space and the user address of the word,
+//! which stay the same when its page is copied on write. Words in shared
+//! areas are keyed by their kernel address, so processes sharing the page
+//! also share the futex.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use config::errno::Errno;
+use config::vm::PTE_R;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::mm::vm;
+use crate::proc::PROC_MANAGER;
+use crate::sync::{SpinLock, WaitQueue};
+
+/// What a futex is known by, see the module doc
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum FutexKey {
+    /// page table and user address
+    Private(usize, usize),
+    /// kernel address
+    Shared(usize),
+}
+
+/// Sleepers by futex, queues are removed once empty
+static FUTEXES: SpinLock<BTreeMap<FutexKey, Arc<WaitQueue>>> =
+    SpinLock::new(BTreeMap::new(), "FutexLock");
+
+/// The key of the aligned user word at uaddr, and f applied to the word.
+/// The address space stays locked meanwhile, so its page can't be freed
+/// or copied on write under f.
+/// Fail with EINVAL if it is not aligned, or EFAULT if it is not mapped.
+fn with_word<R>(uaddr: usize, f: impl FnOnce(&AtomicU32) -> R) -> Result<(FutexKey, R), Errno> {
+    if !uaddr.is_multiple_of(core::mem::size_of::<u32>()) {
+        return Err(Errno::EINVAL);
+    }
+    let mut pm = PROC_MANAGER.lock();
+    let mm = pm.current().mm.lock();
+    let kaddr = vm::useraddr(mm.pagetable, uaddr, PTE_R).ok_or(Errno::EFAULT)?;
+    let key = match mm.vmas.find(uaddr) {
+        Some(vma) if vma.shared => FutexKey::Shared(kaddr),
+        _ => FutexKey::Private(mm.pagetable, uaddr),
+    };
+    let word = unsafe { &*(kaddr as *const AtomicU32) };
+    Ok((key, f(word)))
+}
+
+/// Sleep until woken up by `wake` if the word at uaddr still holds val.
+/// Fail without sleeping with EAGAIN if it doesn't, or EINTR if a signal
+/// is pending.
+pub fn wait(uaddr: usize, val: u32) -> Result<(), Errno> {
+    // lock order: futexes, then the process table and the address space.
+    // wakers change the word before taking the lock, so none is missed
+    let mut futexes = FUTEXES.lock();
+    let (key, word) = with_word(uaddr, |word| word.load(Ordering::SeqCst))?;
+    if word != val {
+        return Err(Errno::EAGAIN);
+    }
+    if crate::signal::interrupted() {
+        return Err(Errno::EINTR);
+    }
+    let queue = futexes.entry(key).or_default().clone();
+    drop(queue.sleep(futexes));
+    Ok(())
+}
+
+/// Wake up at most n threads sleeping on the word at uaddr.
+/// Return how many were woken up.
+pub fn wake(uaddr: usize, n: usize) -> Result<usize, Errno> {
+    let (key, ()) = with_word(uaddr, |_| ())?;
+    let mut futexes = FUTEXES.lock();
+    let Some(queue) = futexes.get(&key).cloned() else {
+        return Ok(0);
+    };
+    let mut woken = 0;
+    while woken < n && queue.wake_one() {
+        woken += 1;
+    }
+    if queue.is_empty() {
+        futexes.remove(&key);
+    }
+    Ok(woken)
+}
@@ -4,6 +4,7 @@
 use alloc::collections::VecDeque;
 
 use crate::console::{CtrlChar, EscapeCode, InputMode};
+use crate::sync::{SpinLock, WaitQueue};
 
 pub struct Stdout;
 
@@ -26,12 +27,30 @@ impl core::fmt::Write for Stdout {
     }
 }
 
-/// TODO: Use lazy_static! after blocking & waking are implemented.
-///   Otherwise, if we use SpinLock, deadlock will occur.
-pub static mut STDIN: Stdin = Stdin::new();
+/// Filled by the UART interrupt handler, read by SYS_READ.
+pub static STDIN: SpinLock<Stdin> = SpinLock::new(Stdin::new(), "StdinLock");
 
-/// TODO: Block a requesting thread if deque is empty,
-///  and wake it up when new chars are available.
+/// Readers waiting for a line of input
+static READERS: WaitQueue = WaitQueue::new();
+
+/// Take a char from stdin, sleeping until one is available.
+/// Return None if a signal interrupts the wait.
+pub fn getchar() -> Option<char> {
+    let mut stdin = STDIN.lock();
+    loop {
+        if let Some(ch) = stdin.pop() {
+            return Some(ch);
+        }
+        if crate::signal::interrupted() {
+            return None;
+        }
+        stdin = READERS.sleep(stdin);
+    }
+}
+
+/// Input from the console.
+/// Readers block while the deque is empty, and are woken up
+/// when a line is flushed.
 #[rustfmt::skip]
 pub struct Stdin {
     /// Character deque.
@@ -114,6 +133,12 @@ impl Stdin {
             CtrlChar::ESC => {
                 self.state = InputMode::EscapeState1;
             }
+            CtrlChar::ETX => {
+                // Ctrl-C drops the line being typed and interrupts everyone
+                self.buffer.clear();
+                println!("^C");
+                crate::signal::kill_all(config::signal::SIGINT);
+            }
             _ => self.buffer.push_back(c),
         }
     }
@@ -123,20 +148,11 @@ impl Stdin {
         for c in self.buffer.drain(..) {
             self.chars.push_back(c);
         }
-        // TODO: Wake up a blocked thread.
+        READERS.wake_all();
     }
 
-    pub fn pop(&mut self) -> char {
-        loop {
-            let c = self.chars.pop_front();
-            if let Some(ch) = c {
-                return ch;
-            } else {
-                // TODO: Block the thread.
-            }
-            // Ideally, when a thread is woken up, the program counter
-            //   goes here and tries to pop again.
-            // The current implementation uses busy waiting.
-        }
+    /// Take a char that is ready to be read, see `getchar` for the blocking version.
+    pub fn pop(&mut self) -> Option<char> {
+        self.chars.pop_front()
     }
 }
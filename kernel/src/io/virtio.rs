@@ -1,9 +1,6 @@
 #![allow(clippy::result_unit_err)]
 
-use core::{
-    ptr::NonNull,
-    sync::atomic::{AtomicUsize, Ordering},
-};
+use core::ptr::NonNull;
 
 use config::vm::PA2VA_OFFSET;
 use fdt::{node::FdtNode, Fdt};
@@ -36,8 +33,8 @@ pub fn init(dtb_pa: usize) {
 fn virtio_mmio_init(node: FdtNode) {
     if let Some(reg) = node.reg().and_then(|mut r| r.next()) {
         let pa = reg.starting_address as usize;
-        // We map MMIO pa to equal va in S mode
-        let va = pa;
+        // MMIO is mapped with the same offset as RAM in S mode
+        let va = pa + PA2VA_OFFSET;
         // get the device header, validate it and initialize the device
         let header = NonNull::new(va as *mut VirtIOHeader).unwrap();
         match unsafe { MmioTransport::new(header) } {
@@ -223,14 +220,6 @@ pub mod gpu {
 }
 struct HalImpl;
 
-extern "C" {
-    fn ekernel();
-}
-
-lazy_static! {
-    static ref DMA_PA: AtomicUsize = AtomicUsize::new(ekernel as usize - PA2VA_OFFSET);
-}
-
 fn virt_to_phys(va: usize) -> PhysAddr {
     va - PA2VA_OFFSET
 }
@@ -238,17 +227,19 @@ fn virt_to_phys(va: usize) -> PhysAddr {
 #[allow(unused_variables)]
 unsafe impl Hal for HalImpl {
     fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
-        let pa = DMA_PA.fetch_add(pages * PAGE_SIZE, Ordering::SeqCst);
-        let va = NonNull::new((pa + PA2VA_OFFSET) as _).unwrap();
-        (pa, va)
+        let va = crate::mm::alloc_contiguous(pages).expect("dma_alloc: out of memory");
+        (virt_to_phys(va), NonNull::new(va as _).unwrap())
     }
 
     unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+        for i in 0..pages {
+            crate::mm::free_page(vaddr.as_ptr() as usize + i * PAGE_SIZE);
+        }
         0
     }
 
     unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
-        NonNull::new(paddr as _).unwrap()
+        NonNull::new((paddr + PA2VA_OFFSET) as _).unwrap()
     }
 
     unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
@@ -15,17 +15,22 @@ global_asm!(include_str!("asm/swtch.S"));
 pub mod util;
 #[macro_use]
 pub mod console;
+pub mod backtrace;
 mod context;
+pub mod cpu;
+pub mod dtb;
 pub mod fs;
+mod futex;
 pub mod io;
-mod loader;
+pub mod loader;
 pub mod logging;
 pub mod mm;
 pub mod proc;
 pub mod sbi;
 pub mod sched;
-mod sync;
-mod syscall;
+mod signal;
+pub mod sync;
+pub mod syscall;
 pub mod trap;
 
 #[macro_use]
@@ -35,7 +40,7 @@ extern crate lazy_static;
 
 extern crate alloc;
 
-pub use context::{Context, TrapFrame};
+pub use context::{Context, TrapFrame, REG_A0, REG_RA, REG_SP};
 
 pub trait Testable {
     fn run(&self);
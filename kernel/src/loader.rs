@@ -1,8 +1,14 @@
 //! ELF loader
 
+use config::{layout::PGSIZE, vm::*};
+
+use crate::fs::Inode;
+use crate::mm::vm;
+use crate::mm::vma::{Vma, VmaList};
+
 /// 64-bit ELF file header
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Default)]
 #[rustfmt::skip]
 struct ElfHeader {
     magic:      [u8; 4],
@@ -24,7 +30,7 @@ struct ElfHeader {
 
 /// 64-bit ELF program header
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Default)]
 #[rustfmt::skip]
 struct ProgramHeader {
     p_type:     [u8; 4],
@@ -37,34 +43,120 @@ struct ProgramHeader {
     align:      [u8; 8],
 }
 
-/// Analyze elf file and load it into memory
-pub fn _load_file(start: usize) -> u64 {
-    // let start = info.offset;
-    let elf = unsafe { &*(start as *const u8 as *const ElfHeader) };
-    assert_eq!(elf.magic, [0x7f, 0x45, 0x4c, 0x46]); // elf magic
-    assert_eq!(elf.arch, [0xf3, 0x00]); // riscv32
-                                        // Load program into memory
-    let mut p = start + u64::from_le_bytes(elf.phoff) as usize;
-    for _ in 0..u16::from_le_bytes(elf.phnum) {
-        let ph = unsafe { &*(p as *const u8 as *const ProgramHeader) };
-        if u32::from_le_bytes(ph.p_type) == 1 {
-            // loadable
-            let offset = u64::from_le_bytes(ph.offset) as usize;
-            let vaddr = u64::from_le_bytes(ph.vaddr) as usize;
-            let filesz = u64::from_le_bytes(ph.filesz) as usize;
-            let memsz = u64::from_le_bytes(ph.memsz) as usize;
-            let mut p = start + offset;
-            for index in 0..filesz {
-                let byte = unsafe { *(p as *const u8) };
-                unsafe { *((index + vaddr) as *mut u8) = byte };
-                p += 1;
-            }
-            for index in filesz..memsz {
-                unsafe { *((index + vaddr) as *mut u8) = 0 };
-            }
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// Read a plain old data struct from the inode at offset off.
+fn read_struct<T: Default>(inode: &Inode, off: usize) -> Option<T> {
+    let mut value = T::default();
+    let size = core::mem::size_of::<T>();
+    let buf = unsafe { core::slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, size) };
+    if inode.read(off, buf) != size {
+        return None;
+    }
+    Some(value)
+}
+
+/// Analyze an elf file and load its PT_LOAD segments into a user page table,
+/// recording an area for each of them in vmas. Segments must be sorted by
+/// address without overlapping, below the mmap and stack areas.
+/// Return the entry point and the page-aligned end of the image,
+/// or None if the file is not a valid executable or memory runs out.
+pub fn load(inode: &Inode, pta: usize, vmas: &mut VmaList) -> Option<(usize, usize)> {
+    let elf: ElfHeader = read_struct(inode, 0)?;
+    if elf.magic != [0x7f, 0x45, 0x4c, 0x46] // elf magic
+        || elf.elf[0] != 2                  // 64-bit
+        || elf.arch != [0xf3, 0x00]
+    // riscv
+    {
+        return None;
+    }
+    let phoff = u64::from_le_bytes(elf.phoff) as usize;
+    let phentsize = u16::from_le_bytes(elf.phentsize) as usize;
+    let mut end = 0;
+    for i in 0..u16::from_le_bytes(elf.phnum) as usize {
+        let ph: ProgramHeader = read_struct(inode, phoff + i * phentsize)?;
+        if u32::from_le_bytes(ph.p_type) == PT_LOAD {
+            end = load_segment(inode, pta, vmas, &ph, end)?;
         }
-        p += u16::from_le_bytes(elf.phentsize) as usize;
     }
+    Some((u64::from_le_bytes(elf.entry) as usize, page_up(end)))
+}
 
-    u64::from_le_bytes(elf.entry)
+/// Map the pages of a loadable segment and copy its content from the file.
+/// Pages are zeroed on allocation, so the part beyond filesz is left as is.
+/// prev is the end of the previous segment, which this one must not overlap.
+/// Return the end address of the segment.
+fn load_segment(
+    inode: &Inode,
+    pta: usize,
+    vmas: &mut VmaList,
+    ph: &ProgramHeader,
+    prev: usize,
+) -> Option<usize> {
+    let flags = u32::from_le_bytes(ph.flags);
+    let offset = u64::from_le_bytes(ph.offset) as usize;
+    let vaddr = u64::from_le_bytes(ph.vaddr) as usize;
+    let filesz = u64::from_le_bytes(ph.filesz) as usize;
+    let memsz = u64::from_le_bytes(ph.memsz) as usize;
+    if filesz > memsz || vaddr < prev || vaddr.checked_add(memsz)? > config::layout::MMAP_TOP {
+        return None;
+    }
+    // no access at all, or writable but not readable, which the
+    // page table can't express
+    if flags & PF_R == 0 && (flags & PF_W != 0 || flags & PF_X == 0) {
+        return None;
+    }
+    let mut perm = 0;
+    if flags & PF_R != 0 {
+        perm |= PTE_R;
+    }
+    if flags & PF_W != 0 {
+        perm |= PTE_W;
+    }
+    if flags & PF_X != 0 {
+        perm |= PTE_X;
+    }
+
+    // segments sharing a page keep the permissions of the first one
+    let mut start = page_down(vaddr);
+    if vmas.find(start).is_some() {
+        start += PGSIZE;
+    }
+    let end = page_up(vaddr + memsz);
+    if start < end {
+        if vmas.overlaps(start, end) {
+            return None;
+        }
+        vmas.insert(Vma {
+            start,
+            end,
+            perm,
+            shared: false,
+            file: None,
+        });
+    }
+
+    let mut va = page_down(vaddr);
+    while va < vaddr + memsz {
+        if vm::walkaddr(pta, va).is_none() {
+            vm::uvmalloc(pta, va, PGSIZE, perm)?;
+        }
+        va += PGSIZE;
+    }
+
+    let mut copied = 0;
+    while copied < filesz {
+        let va = vaddr + copied;
+        let n = (filesz - copied).min(PGSIZE - va % PGSIZE);
+        let dst = vm::walkaddr(pta, va)?;
+        let buf = unsafe { core::slice::from_raw_parts_mut(dst as *mut u8, n) };
+        if inode.read(offset + copied, buf) != n {
+            return None;
+        }
+        copied += n;
+    }
+    Some(vaddr + memsz)
 }
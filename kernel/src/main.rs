@@ -1,6 +1,5 @@
 #![no_std]
 #![no_main]
-#![feature(panic_info_message)]
 #![feature(custom_test_frameworks)]
 #![test_runner(kernel::test_runner)]
 #![reexport_test_harness_main = "test_main"]
@@ -12,16 +11,16 @@ pub extern "C" fn os_main(hartid: usize, dtb_pa: usize) -> ! {
     test_main();
 
     kernel::logging  ::init();
+    kernel::dtb      ::init(dtb_pa);
     kernel::mm       ::init();
     kernel::io       ::init(dtb_pa);
     kernel::trap     ::init(hartid);
     kernel::fs       ::init();
-    #[allow(clippy::empty_loop)]
-    loop {}
-    // TODO: User mode page table
-    // kernel::proc     ::init();
+    kernel::cpu      ::init(hartid, dtb_pa);
+    kernel::proc     ::init();
 }
 
+/// Report the panic with a backtrace, then power off.
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
@@ -30,12 +29,13 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
             "Panicked at {}:{} {}",
             location.file(),
             location.line(),
-            info.message().unwrap()
+            info.message()
         );
     } else {
-        log::error!("Panicked: {}", info.message().unwrap());
+        log::error!("Panicked: {}", info.message());
     }
-    loop {}
+    kernel::backtrace::print();
+    kernel::sbi::shutdown()
 }
 
 #[cfg(test)]
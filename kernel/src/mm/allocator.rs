@@ -1,30 +1,133 @@
-//! TODO: use our own allocator and make a benchmark
+//! Kernel heap: a slab allocator with power-of-two size classes.
+//! Small objects are carved out of frames, one frame per refill of a class,
+//! and larger ones take whole frames from the frame allocator.
 
-use buddy_system_allocator::LockedHeap;
-use config::layout::*;
+use alloc::alloc::{GlobalAlloc, Layout};
+use config::{layout::*, vm::page_up};
 
-use alloc::alloc::{alloc, Layout};
+use crate::mm::frame;
+use crate::sync::SpinLock;
 
-#[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap<32> = LockedHeap::empty();
+/// Object sizes of the slab caches, larger objects take whole frames
+pub const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
-static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
+#[global_allocator]
+static HEAP_ALLOCATOR: LockedSlab = LockedSlab(SpinLock::new(Slab::new(), "HeapLock"));
 
-#[repr(align(4096), C)]
-struct _PAGE {
-    _data: [u8; PGSIZE],
+#[derive(Default)]
+pub struct Slab {
+    /// free objects of each size class, linked through their first word
+    free: [usize; SIZE_CLASSES.len()],
+    /// frames taken from the frame allocator
+    frames: usize,
+    /// bytes asked for by live allocations
+    user: usize,
 }
 
-const PAGE_LAYOUT: Layout = Layout::new::<_PAGE>();
+impl Slab {
+    pub const fn new() -> Self {
+        Self {
+            free: [0; SIZE_CLASSES.len()],
+            frames: 0,
+            user: 0,
+        }
+    }
+
+    /// Allocate memory for layout, null when memory runs out.
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = match size_class(layout) {
+            Some(class) => self.alloc_object(class),
+            None => self.alloc_frames(layout),
+        };
+        if !ptr.is_null() {
+            self.user += layout.size();
+        }
+        ptr
+    }
+
+    /// Free memory allocated with the same layout.
+    pub fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.user -= layout.size();
+        match size_class(layout) {
+            Some(class) => {
+                // freed objects stay in the cache of their class
+                unsafe { *(ptr as *mut usize) = self.free[class] };
+                self.free[class] = ptr as usize;
+            }
+            None => {
+                let pages = page_up(layout.size()) / PGSIZE;
+                for i in 0..pages {
+                    frame::free_page(ptr as usize + i * PGSIZE);
+                }
+                self.frames -= pages;
+            }
+        }
+    }
 
-pub fn init() {
-    unsafe {
-        HEAP_ALLOCATOR
-            .lock()
-            .init(HEAP_SPACE.as_ptr() as usize, KERNEL_HEAP_SIZE);
+    /// Bytes asked for by live allocations
+    pub fn stats_alloc_user(&self) -> usize {
+        self.user
     }
+
+    /// Bytes of the frames held, including free objects of the caches
+    pub fn stats_total_bytes(&self) -> usize {
+        self.frames * PGSIZE
+    }
+
+    fn alloc_object(&mut self, class: usize) -> *mut u8 {
+        if self.free[class] == 0 {
+            let Some(page) = frame::try_alloc_page() else {
+                return core::ptr::null_mut();
+            };
+            self.frames += 1;
+            let size = SIZE_CLASSES[class];
+            for obj in (page..page + PGSIZE).step_by(size).rev() {
+                unsafe { *(obj as *mut usize) = self.free[class] };
+                self.free[class] = obj;
+            }
+        }
+        let obj = self.free[class];
+        self.free[class] = unsafe { *(obj as *const usize) };
+        obj as *mut u8
+    }
+
+    /// Objects of more than one page take contiguous frames, see
+    /// `frame::alloc_contiguous`. They go back one by one when freed.
+    fn alloc_frames(&mut self, layout: Layout) -> *mut u8 {
+        if layout.align() > PGSIZE {
+            return core::ptr::null_mut();
+        }
+        let pages = page_up(layout.size()) / PGSIZE;
+        let start = if pages == 1 {
+            frame::try_alloc_page()
+        } else {
+            frame::alloc_contiguous(pages)
+        };
+        match start {
+            Some(start) => {
+                self.frames += pages;
+                start as *mut u8
+            }
+            None => core::ptr::null_mut(),
+        }
+    }
+}
+
+/// The smallest size class fitting layout, whose objects are aligned to
+/// their size within page-aligned frames. None if it takes whole frames.
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| class >= size)
 }
 
-pub fn alloc_page() -> usize {
-    unsafe { alloc(PAGE_LAYOUT) as usize }
+pub struct LockedSlab(SpinLock<Slab>);
+
+unsafe impl GlobalAlloc for LockedSlab {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().dealloc(ptr, layout)
+    }
 }
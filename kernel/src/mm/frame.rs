@@ -0,0 +1,225 @@
+//! Physical frame allocator, managing all RAM the kernel and firmware don't use.
+//! Frames are named by their kernel virtual address in the linear map,
+//! and each has a reference count for sharing and copy-on-write.
+
+use config::{layout::*, vm::*};
+
+use crate::dtb::Regions;
+use crate::sync::SpinLock;
+
+pub struct FrameAllocator {
+    /// frames in [base, end) have a reference count, free or not
+    base: usize,
+    end: usize,
+    /// frames never handed out
+    fresh: Regions,
+    /// freed frames, doubly linked through their first two words, see `Link`
+    recycled: usize,
+    /// reference count of each frame from base, 0 when it is free,
+    /// UNMANAGED for the holes between free ranges
+    refs: &'static mut [u16],
+    /// number of free frames
+    free: usize,
+}
+
+static FRAMES: SpinLock<FrameAllocator> = SpinLock::new(FrameAllocator::new(), "FramesLock");
+
+/// Reference count of frames that are not RAM to allocate
+const UNMANAGED: u16 = u16::MAX;
+
+/// Neighbours of a frame in the recycled list, 0 at the ends
+#[repr(C)]
+struct Link {
+    next: usize,
+    prev: usize,
+}
+
+fn link(frame: usize) -> &'static mut Link {
+    unsafe { &mut *(frame as *mut Link) }
+}
+
+impl FrameAllocator {
+    const fn new() -> Self {
+        Self {
+            base: 0,
+            end: 0,
+            fresh: Regions::new(),
+            recycled: 0,
+            refs: &mut [],
+            free: 0,
+        }
+    }
+
+    /// Manage the physical memory in free.
+    /// The reference count table takes the first pages large enough for it.
+    fn init(&mut self, free: &Regions) {
+        self.fresh = Regions::new();
+        for (start, end) in free.iter() {
+            self.fresh
+                .add(page_up(start) + PA2VA_OFFSET, page_down(end) + PA2VA_OFFSET);
+        }
+        let (Some((base, _)), Some((_, end))) =
+            (self.fresh.iter().next(), self.fresh.iter().last())
+        else {
+            panic!("no free memory");
+        };
+        let frames = (end - base) / PGSIZE;
+        let table = self
+            .fresh
+            .take(page_up(frames * core::mem::size_of::<u16>()))
+            .expect("no room for the frame reference counts");
+        self.base = base;
+        self.end = end;
+        self.recycled = 0;
+        self.refs = unsafe { core::slice::from_raw_parts_mut(table as *mut u16, frames) };
+        self.refs.fill(UNMANAGED);
+        for (start, end) in self.fresh.iter() {
+            self.refs[(start - base) / PGSIZE..(end - base) / PGSIZE].fill(0);
+        }
+        self.free = self.fresh.iter().map(|(s, e)| (e - s) / PGSIZE).sum();
+    }
+
+    fn index(&self, frame: usize) -> usize {
+        let managed = frame.is_multiple_of(PGSIZE)
+            && frame >= self.base
+            && frame < self.end
+            && self.refs[(frame - self.base) / PGSIZE] != UNMANAGED;
+        assert!(managed, "frame {:#x} not managed", frame);
+        (frame - self.base) / PGSIZE
+    }
+
+    fn alloc(&mut self) -> Option<usize> {
+        let frame = if self.recycled != 0 {
+            let frame = self.recycled;
+            self.unlink(frame);
+            frame
+        } else {
+            self.fresh.take(PGSIZE)?
+        };
+        let idx = self.index(frame);
+        self.refs[idx] = 1;
+        self.free -= 1;
+        Some(frame)
+    }
+
+    /// Take n contiguous frames from those never handed out, or else from
+    /// a run of freed frames found in the reference counts.
+    fn alloc_contiguous(&mut self, n: usize) -> Option<usize> {
+        let frame = match self.fresh.take(n * PGSIZE) {
+            Some(frame) => frame,
+            None => {
+                let frame = self.find_recycled(n)?;
+                for i in 0..n {
+                    self.unlink(frame + i * PGSIZE);
+                }
+                frame
+            }
+        };
+        let idx = self.index(frame);
+        self.refs[idx..idx + n].fill(1);
+        self.free -= n;
+        Some(frame)
+    }
+
+    /// The first run of n frames that are all in the recycled list
+    fn find_recycled(&self, n: usize) -> Option<usize> {
+        let mut run = 0;
+        for (idx, &refs) in self.refs.iter().enumerate() {
+            let frame = self.base + idx * PGSIZE;
+            let fresh = self.fresh.iter().any(|(s, e)| frame >= s && frame < e);
+            run = if refs == 0 && !fresh { run + 1 } else { 0 };
+            if run == n {
+                return Some(frame - (n - 1) * PGSIZE);
+            }
+        }
+        None
+    }
+
+    /// Take a frame out of the recycled list.
+    fn unlink(&mut self, frame: usize) {
+        let Link { next, prev } = *link(frame);
+        if prev == 0 {
+            self.recycled = next;
+        } else {
+            link(prev).next = next;
+        }
+        if next != 0 {
+            link(next).prev = prev;
+        }
+    }
+
+    fn share(&mut self, frame: usize) {
+        let idx = self.index(frame);
+        assert!(self.refs[idx] > 0, "share_page: frame {:#x} is free", frame);
+        self.refs[idx] += 1;
+    }
+
+    fn refcount(&self, frame: usize) -> usize {
+        self.refs[self.index(frame)] as usize
+    }
+
+    fn free(&mut self, frame: usize) {
+        let idx = self.index(frame);
+        assert!(self.refs[idx] > 0, "free_page: double free of {:#x}", frame);
+        self.refs[idx] -= 1;
+        if self.refs[idx] == 0 {
+            *link(frame) = Link {
+                next: self.recycled,
+                prev: 0,
+            };
+            if self.recycled != 0 {
+                link(self.recycled).prev = frame;
+            }
+            self.recycled = frame;
+            self.free += 1;
+        }
+    }
+}
+
+/// Hand the free RAM of the machine to the frame allocator, see `dtb::Machine`.
+/// Frames are handed out from the bottom, which start.S maps until `vm::init`
+/// maps all of RAM.
+pub fn init() {
+    FRAMES.lock().init(&crate::dtb::machine().free_memory());
+}
+
+/// Allocate a zeroed page, return its virtual address.
+pub fn alloc_page() -> usize {
+    try_alloc_page().expect("alloc_page: out of memory")
+}
+
+/// Allocate a zeroed page, or return None when memory runs out.
+pub fn try_alloc_page() -> Option<usize> {
+    let page = FRAMES.lock().alloc()?;
+    unsafe { core::ptr::write_bytes(page as *mut u8, 0, PGSIZE) };
+    Some(page)
+}
+
+/// Allocate n physically contiguous zeroed pages, e.g. for DMA.
+/// Return the virtual address of the first one. They are freed one by one,
+/// and found together again once all are freed.
+pub fn alloc_contiguous(n: usize) -> Option<usize> {
+    let start = FRAMES.lock().alloc_contiguous(n)?;
+    unsafe { core::ptr::write_bytes(start as *mut u8, 0, n * PGSIZE) };
+    Some(start)
+}
+
+/// Add an owner to a page, e.g. when it is shared copy-on-write.
+pub fn share_page(va: usize) {
+    FRAMES.lock().share(va);
+}
+
+/// Number of owners of a page.
+pub fn page_refcount(va: usize) -> usize {
+    FRAMES.lock().refcount(va)
+}
+
+/// Drop an owner of a page, and free it if it was the last one.
+pub fn free_page(va: usize) {
+    FRAMES.lock().free(va);
+}
+
+/// Number of free pages left.
+pub fn free_pages() -> usize {
+    FRAMES.lock().free
+}
@@ -0,0 +1,67 @@
+//! Kernel stacks of processes. Each is mapped in its own slot of the kernel
+//! stack region, above an unmapped guard, see `config::layout::KSTACK_BASE`.
+//! An overflow faults on the guard instead of corrupting other memory.
+
+use alloc::vec::Vec;
+use config::layout::*;
+
+use crate::mm::vm;
+use crate::sync::SpinLock;
+
+struct Slots {
+    /// slots above it were never used
+    next: usize,
+    /// slots freed since
+    free: Vec<usize>,
+}
+
+static SLOTS: SpinLock<Slots> = SpinLock::new(
+    Slots {
+        next: 0,
+        free: Vec::new(),
+    },
+    "KernelStackSlotsLock",
+);
+
+/// A kernel stack, unmapped and freed on drop
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Map a zeroed stack in a free slot.
+    /// Return None when memory or slots run out.
+    pub fn new() -> Option<Self> {
+        let mut slots = SLOTS.lock();
+        let slot = match slots.free.pop() {
+            Some(slot) => slot,
+            None if slots.next < KSTACK_REGION / KSTACK_SLOT => {
+                slots.next += 1;
+                slots.next - 1
+            }
+            None => return None,
+        };
+        drop(slots);
+        let stack = Self { slot };
+        vm::kvmalloc(stack.top() - STACKSIZE, STACKSIZE)?;
+        Some(stack)
+    }
+
+    /// The initial stack pointer, the stack grows down from here
+    pub fn top(&self) -> usize {
+        KSTACK_BASE + (self.slot + 1) * KSTACK_SLOT
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        vm::kvmdealloc(self.top() - STACKSIZE, STACKSIZE);
+        SLOTS.lock().free.push(self.slot);
+    }
+}
+
+/// Whether va is in the guard below a kernel stack
+pub fn in_guard(va: usize) -> bool {
+    (KSTACK_BASE..KSTACK_BASE + KSTACK_REGION).contains(&va)
+        && (va - KSTACK_BASE) % KSTACK_SLOT < KSTACK_SLOT - STACKSIZE
+}
@@ -1,7 +1,16 @@
 mod allocator;
-mod vm;
+mod frame;
+pub mod kstack;
+pub mod uaccess;
+pub mod vm;
+pub mod vma;
+
+pub use allocator::{Slab, SIZE_CLASSES};
+pub use frame::{
+    alloc_contiguous, alloc_page, free_page, free_pages, page_refcount, share_page, try_alloc_page,
+};
 
 pub fn init() {
-    allocator::init();
+    frame::init();
     vm::init();
 }
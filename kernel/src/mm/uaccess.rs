@@ -0,0 +1,88 @@
+//! Copies between the kernel and the memory of the current process.
+//! Syscalls never dereference user pointers: addresses are translated
+//! through the page table of the caller and checked for the access user
+//! code would need, so a bad pointer fails the syscall with EFAULT.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use config::errno::Errno;
+use config::layout::PGSIZE;
+use config::vm::{PTE_R, PTE_W};
+
+use crate::mm::vm;
+use crate::proc::PROC_MANAGER;
+
+/// Call f with the kernel address and the length of each piece of
+/// [va, va + len) within a page, checking access, PTE_R or PTE_W.
+/// Pages not mapped yet or copy-on-write are handled as if the user
+/// touched them, see `VmaList::fault`.
+/// Fail with EFAULT at the first page the caller can't access that way.
+fn for_each_page(
+    va: usize,
+    len: usize,
+    access: usize,
+    mut f: impl FnMut(usize, usize),
+) -> Result<(), Errno> {
+    let mm = PROC_MANAGER.lock().current().mm.clone();
+    let mm = mm.lock();
+    let end = va.checked_add(len).ok_or(Errno::EFAULT)?;
+    let mut va = va;
+    while va < end {
+        let n = (end - va).min(PGSIZE - va % PGSIZE);
+        let kva = match vm::useraddr(mm.pagetable, va, access) {
+            Some(kva) => kva,
+            None => {
+                if mm.vmas.fault(mm.pagetable, va, access).is_err() {
+                    return Err(Errno::EFAULT);
+                }
+                vm::useraddr(mm.pagetable, va, access).ok_or(Errno::EFAULT)?
+            }
+        };
+        f(kva, n);
+        va += n;
+    }
+    Ok(())
+}
+
+/// Copy dst.len() bytes from the user address src.
+/// Fail with EFAULT if the caller can't read all of them.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), Errno> {
+    let mut copied = 0;
+    for_each_page(src, dst.len(), PTE_R, |kva, n| {
+        let from = unsafe { core::slice::from_raw_parts(kva as *const u8, n) };
+        dst[copied..copied + n].copy_from_slice(from);
+        copied += n;
+    })
+}
+
+/// Copy src to the user address dst.
+/// Fail with EFAULT if the caller can't write all of it.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), Errno> {
+    let mut copied = 0;
+    for_each_page(dst, src.len(), PTE_W, |kva, n| {
+        let to = unsafe { core::slice::from_raw_parts_mut(kva as *mut u8, n) };
+        to.copy_from_slice(&src[copied..copied + n]);
+        copied += n;
+    })
+}
+
+/// Copy a '\0' terminated string from the user address src,
+/// taking at most max bytes with the '\0'.
+/// Fail with EFAULT if it can't be read, ENAMETOOLONG if it is longer,
+/// or EINVAL if it is not UTF-8.
+pub fn copy_str_from_user(src: usize, max: usize) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+    while bytes.len() < max {
+        // up to the end of the page, so the next one is only read if needed
+        let va = src.checked_add(bytes.len()).ok_or(Errno::EFAULT)?;
+        let start = bytes.len();
+        bytes.resize(start + (PGSIZE - va % PGSIZE).min(max - start), 0);
+        copy_from_user(&mut bytes[start..], va)?;
+        if let Some(len) = bytes[start..].iter().position(|&b| b == 0) {
+            bytes.truncate(start + len);
+            return String::from_utf8(bytes).map_err(|_| Errno::EINVAL);
+        }
+    }
+    Err(Errno::ENAMETOOLONG)
+}
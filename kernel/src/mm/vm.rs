@@ -2,42 +2,91 @@
 
 use alloc::boxed::Box;
 use config::{layout::*, vm::*};
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
 
-use crate::mm::allocator::alloc_page;
+use crate::mm::frame::{alloc_page, free_page, page_refcount, share_page, try_alloc_page};
 
 lazy_static! {
     static ref ROOT_PT: Box<PageTable> = Box::new(PageTable::new());
 }
 
-/// Global allocator should be initialized before calling this function.
+/// The page table in use on each hart, see `shootdown`
+static ACTIVE: [AtomicUsize; NCPU] = [const { AtomicUsize::new(0) }; NCPU];
+
+/// The first root PTE index of the kernel half.
+/// Entries from here on are shared by all user page tables.
+const KERNEL_HALF: usize = 256;
+
+/// The frame allocator should be initialized before calling this function.
+/// Map the kernel image section by section, the PLIC and MMIO devices,
+/// and the RAM described by the device tree, see `dtb::Machine`.
 pub fn init() {
     extern "C" {
         fn stext();
         fn srodata();
         fn sdata();
+        fn ekernel();
     }
 
     let pta = ROOT_PT.as_ref() as *const PageTable as usize;
+    let machine = crate::dtb::machine();
 
-    let stxt_pa = PHY_START + OPENSBI_SIZE;
-    let txt_len = srodata as usize - stext as usize;
+    let stxt_pa = stext as *const () as usize - PA2VA_OFFSET;
+    let txt_len = srodata as *const () as usize - stext as *const () as usize;
 
     let srod_pa = stxt_pa + txt_len;
-    let rod_len = sdata as usize - srodata as usize;
+    let rod_len = sdata as *const () as usize - srodata as *const () as usize;
 
     let rest_pa = srod_pa + rod_len;
-    let rest_len = PHY_STOP - rest_pa;
+    let rest_len = ekernel as *const () as usize - sdata as *const () as usize;
 
-    kvmmap(pta, PLIC_BASE, PLIC_BASE, PLIC_MMAP_SIZE, PTE_R | PTE_W);
-    kvmmap(pta, MMIO_BASE, MMIO_BASE, MMIO_MMAP_SIZE, PTE_R | PTE_W);
-    kvmmap(pta, stext as usize, stxt_pa, txt_len, PTE_R | PTE_X);
-    kvmmap(pta, srodata as usize, srod_pa, rod_len, PTE_R);
-    kvmmap(pta, sdata as usize, rest_pa, rest_len, PTE_R | PTE_W);
+    let (plic_start, plic_end) = machine.plic;
+    kvmmap(
+        pta,
+        plic_start + PA2VA_OFFSET,
+        plic_start,
+        plic_end - plic_start,
+        PTE_R | PTE_W,
+    );
+    kvmmap(
+        pta,
+        MMIO_BASE + PA2VA_OFFSET,
+        MMIO_BASE,
+        MMIO_MMAP_SIZE,
+        PTE_R | PTE_W,
+    );
+    kvmmap(
+        pta,
+        stext as *const () as usize,
+        stxt_pa,
+        txt_len,
+        PTE_R | PTE_X,
+    );
+    kvmmap(pta, srodata as *const () as usize, srod_pa, rod_len, PTE_R);
+    kvmmap(
+        pta,
+        sdata as *const () as usize,
+        rest_pa,
+        rest_len,
+        PTE_R | PTE_W,
+    );
+    for (start, end) in machine.mapped_memory().iter() {
+        kvmmap(pta, start + PA2VA_OFFSET, start, end - start, PTE_R | PTE_W);
+    }
+    // User page tables copy the root entries when they are created,
+    // so the one of the kernel stack region must exist from the start.
+    get_pte(pta, KSTACK_BASE, 2).expect("kvmmap: out of memory");
 
     ROOT_PT.flush();
     info!("Initialized MMU, mode: Sv39, root page table @ 0x{:x}", pta);
 }
 
+/// Switch to the kernel page table, on a secondary hart coming from the boot
+/// page table, or when the page table of the last process may be freed.
+pub fn use_kernel_pagetable() {
+    ROOT_PT.flush();
+}
+
 /// Page Table
 /// - All PTEs fit in one page
 #[repr(align(4096), C)]
@@ -55,6 +104,8 @@ impl PageTable {
     fn flush(&self) {
         use riscv::register::satp;
 
+        // before the switch, so that `shootdown` can't miss a change
+        ACTIVE[cpuid!()].store(self as *const PageTable as usize, Ordering::SeqCst);
         unsafe {
             satp::set(
                 satp::Mode::Sv39,
@@ -64,6 +115,16 @@ impl PageTable {
             riscv::asm::sfence_vma_all();
         }
     }
+
+    /// Switch to this page table unless it is already in use.
+    fn activate(&self) {
+        use riscv::register::satp;
+
+        let ppn = (self as *const PageTable as usize - PA2VA_OFFSET) >> PGSHIFT;
+        if satp::read().ppn() != ppn {
+            self.flush();
+        }
+    }
 }
 
 impl core::ops::Index<usize> for PageTable {
@@ -105,6 +166,11 @@ impl PageTableEntry {
         self.bits = (ppn << PTE_SHIFT) | flag | PTE_V;
     }
 
+    #[inline(always)]
+    fn flags(&self) -> usize {
+        self.bits & ((1 << PTE_SHIFT) - 1)
+    }
+
     #[inline(always)]
     fn is(&self, flag: usize) -> bool {
         self.bits & flag != 0
@@ -124,42 +190,392 @@ impl PageTableEntry {
 }
 
 /// Walk through the page table and get the last level PTE for a virtual address.
-/// The function creates new entries if needed.
+/// The function creates new entries if needed, and returns None
+/// if there is no memory left for them.
 /// - pta: Virtual address of the page table
 /// - va: Virtual address to be processed
 /// - level: The level of the page table
 ///     - lv3 pt: 2 == Accessing PPN\[2\]
 ///     - lv2 pt: 1 == Accessing PPN\[1\]
 ///     - lv1 pt: 0 == Accessing PPN\[0\]
-fn get_pte(pta: usize, va: usize, level: usize) -> &'static mut PageTableEntry {
+fn get_pte(pta: usize, va: usize, level: usize) -> Option<&'static mut PageTableEntry> {
     assert!(level <= 2);
     let pt = unsafe { &mut *(pta as *mut PageTable) };
     let pte = &mut pt[vpn(va, level)];
     if level == 0 {
-        return pte;
+        return Some(pte);
     }
     if pte.is(PTE_V) {
         // query from the next level page table
         get_pte(pte.va(), va, level - 1)
     } else {
         // create next level page table
-        let new_pt = alloc_page();
+        let new_pt = try_alloc_page()?;
         pte.link(new_pt);
         get_pte(new_pt, va, level - 1)
     }
 }
 
+/// Look up a virtual address in a page table without creating entries.
+/// Return None if any level of the walk is not valid.
+fn walk(pta: usize, va: usize) -> Option<&'static mut PageTableEntry> {
+    let mut pt = unsafe { &mut *(pta as *mut PageTable) };
+    for level in (1..=2).rev() {
+        let pte = &pt[vpn(va, level)];
+        if !pte.is(PTE_V) {
+            return None;
+        }
+        pt = unsafe { &mut *(pte.va() as *mut PageTable) };
+    }
+    let pte = &mut pt[vpn(va, 0)];
+    if pte.is(PTE_V) {
+        Some(pte)
+    } else {
+        None
+    }
+}
+
+/// Flush the TLB entries of the user region [va, va + size) on this hart,
+/// and on the other harts running on page table pta: the threads of the
+/// process there may still use the old mappings of freed or copied pages.
+fn shootdown(pta: usize, va: usize, size: usize) {
+    unsafe { riscv::asm::sfence_vma_all() };
+    // the PTEs are changed before looking for the harts using them
+    fence(Ordering::SeqCst);
+    let me = cpuid!();
+    let mask = (0..NCPU)
+        .filter(|&id| id != me && ACTIVE[id].load(Ordering::SeqCst) == pta)
+        .fold(0, |mask, id| mask | 1 << id);
+    if mask != 0 {
+        crate::sbi::remote_sfence_vma(mask, page_down(va), page_up(va + size) - page_down(va));
+    }
+}
+
 fn kvmmap(pta: usize, va: usize, pa: usize, size: usize, flag: usize) {
     let mut addr = page_down(va);
-    let end = page_down(va + size);
+    // the page at va + size belongs to the next range
+    let end = page_up(va + size);
     let mut pa = page_down(pa);
-    loop {
-        let pte = get_pte(pta, addr, 2);
+    while addr < end {
+        let pte = get_pte(pta, addr, 2).expect("kvmmap: out of memory");
+        pte.set_pa(pa, flag);
+        addr += PGSIZE;
+        pa += PGSIZE;
+    }
+}
+
+/// Map pages for [va, va + size) to [pa, pa + size) in a page table.
+/// Both va and pa should be page aligned.
+/// Return None if a page-table page can't be allocated.
+fn mappages(pta: usize, va: usize, pa: usize, size: usize, flag: usize) -> Option<()> {
+    let end = page_up(va + size);
+    let mut addr = va;
+    let mut pa = pa;
+    while addr < end {
+        let pte = get_pte(pta, addr, 2)?;
+        assert!(!pte.is(PTE_V), "mappages: remap {:#x}", addr);
         pte.set_pa(pa, flag);
         addr += PGSIZE;
         pa += PGSIZE;
-        if addr > end {
-            break;
+    }
+    Some(())
+}
+
+/// Create an empty user page table.
+/// The kernel half is shared with ROOT_PT, so that the kernel keeps running
+/// on whichever page table is installed when it traps in from user mode.
+/// Root entries added to ROOT_PT afterwards are not propagated.
+pub fn uvmcreate() -> usize {
+    let pta = alloc_page();
+    let pt = unsafe { &mut *(pta as *mut PageTable) };
+    for i in KERNEL_HALF..512 {
+        pt[i] = ROOT_PT[i];
+    }
+    pta
+}
+
+/// Map a user region [va, va + size) to [pa, pa + size).
+/// PTE_U is added to the given flags. Return None when out of memory.
+pub fn uvmmap(pta: usize, va: usize, pa: usize, size: usize, flag: usize) -> Option<()> {
+    assert!(va + size <= USER_TOP, "uvmmap: {:#x} out of user space", va);
+    mappages(pta, va, pa, size, flag | PTE_U)
+}
+
+/// Allocate zeroed pages for the user region [va, va + size) and map them.
+/// When memory runs out, the pages mapped so far are freed and None is returned.
+pub fn uvmalloc(pta: usize, va: usize, size: usize, flag: usize) -> Option<()> {
+    let start = page_down(va);
+    let mut addr = start;
+    while addr < va + size {
+        let mapped = try_alloc_page().and_then(|page| {
+            uvmmap(pta, addr, page - PA2VA_OFFSET, PGSIZE, flag).or_else(|| {
+                free_page(page);
+                None
+            })
+        });
+        if mapped.is_none() {
+            uvmdealloc(pta, start, addr - start);
+            return None;
+        }
+        addr += PGSIZE;
+    }
+    Some(())
+}
+
+/// Allocate the page-table pages of the user region [va, va + size) without
+/// mapping anything, so that mapping it later can't run out of memory.
+/// Return None when out of memory, the pages allocated so far are kept.
+pub fn uvmreserve(pta: usize, va: usize, size: usize) -> Option<()> {
+    let mut addr = page_down(va);
+    while addr < va + size {
+        get_pte(pta, addr, 2)?;
+        addr += PGSIZE;
+    }
+    Some(())
+}
+
+/// Unmap the user region [va, va + size) and free its pages.
+/// Pages that are not mapped are skipped.
+pub fn uvmdealloc(pta: usize, va: usize, size: usize) {
+    let mut addr = page_down(va);
+    while addr < va + size {
+        if let Some(pte) = walk(pta, addr) {
+            if pte.is(PTE_U) {
+                free_page(pte.va());
+                pte.bits = 0;
+            }
+        }
+        addr += PGSIZE;
+    }
+    shootdown(pta, va, size);
+}
+
+/// Allocate zeroed pages for the kernel region [va, va + size) and map them
+/// read-write in all page tables. When memory runs out, the pages mapped so
+/// far are freed and None is returned.
+/// The region must be within a root entry that exists already, see `init`.
+pub fn kvmalloc(va: usize, size: usize) -> Option<()> {
+    let pta = ROOT_PT.as_ref() as *const PageTable as usize;
+    let start = page_down(va);
+    let mut addr = start;
+    while addr < va + size {
+        let mapped = try_alloc_page().and_then(|page| {
+            mappages(pta, addr, page - PA2VA_OFFSET, PGSIZE, PTE_R | PTE_W).or_else(|| {
+                free_page(page);
+                None
+            })
+        });
+        if mapped.is_none() {
+            kvmdealloc(start, addr - start);
+            return None;
+        }
+        addr += PGSIZE;
+    }
+    // this hart may still cache an older mapping of the region, other
+    // harts flush their TLB before running a process, see `switch_task`
+    unsafe { riscv::asm::sfence_vma_all() };
+    Some(())
+}
+
+/// Unmap the kernel region [va, va + size) and free its pages.
+pub fn kvmdealloc(va: usize, size: usize) {
+    let pta = ROOT_PT.as_ref() as *const PageTable as usize;
+    let mut addr = page_down(va);
+    while addr < va + size {
+        if let Some(pte) = walk(pta, addr) {
+            free_page(pte.va());
+            pte.bits = 0;
+        }
+        addr += PGSIZE;
+    }
+    unsafe { riscv::asm::sfence_vma_all() };
+}
+
+/// Change the permissions of the mapped pages of the user region [va, va + size)
+/// to flag, PTE_U is added. A private page shared after fork stays read-only
+/// copy-on-write when it becomes writable.
+pub fn uvmprotect(pta: usize, va: usize, size: usize, flag: usize) {
+    let mut addr = page_down(va);
+    while addr < va + size {
+        if let Some(pte) = walk(pta, addr) {
+            if pte.is(PTE_U) {
+                let mut flag = flag | PTE_U;
+                if flag & PTE_W != 0 && flag & PTE_SHARED == 0 && page_refcount(pte.va()) > 1 {
+                    flag = (flag & !PTE_W) | PTE_COW;
+                }
+                pte.set_pa(pte.va() - PA2VA_OFFSET, flag);
+            }
+        }
+        addr += PGSIZE;
+    }
+    shootdown(pta, va, size);
+}
+
+/// Translate a user virtual address to the kernel virtual address
+/// of the same byte. Return None if it is not mapped for user.
+pub fn walkaddr(pta: usize, va: usize) -> Option<usize> {
+    if va >= USER_TOP {
+        return None;
+    }
+    let pte = walk(pta, va)?;
+    if !pte.is(PTE_U) {
+        return None;
+    }
+    Some(pte.va() + va % PGSIZE)
+}
+
+/// Like `walkaddr`, but also require the permissions in access,
+/// e.g. PTE_W for the kernel to write there for the user.
+/// Copy-on-write pages are not writable until copied, see `cow_fault`.
+pub fn useraddr(pta: usize, va: usize, access: usize) -> Option<usize> {
+    if va >= USER_TOP {
+        return None;
+    }
+    let pte = walk(pta, va)?;
+    if !pte.is(PTE_U) || pte.flags() & access != access {
+        return None;
+    }
+    Some(pte.va() + va % PGSIZE)
+}
+
+/// Install a page table on the current hart.
+/// - pta: Virtual address of the root page table
+pub fn activate(pta: usize) {
+    let pt = unsafe { &*(pta as *const PageTable) };
+    pt.activate();
+}
+
+/// Switch to a page table and flush the TLB, even if it is already in use.
+/// A process coming from another hart may have changed its mappings there.
+pub fn flush(pta: usize) {
+    let pt = unsafe { &*(pta as *const PageTable) };
+    pt.flush();
+}
+
+/// Call f on every valid leaf PTE in the user half of a page table.
+fn for_each_user_pte(pta: usize, f: &mut dyn FnMut(usize, &mut PageTableEntry)) {
+    fn walk_level(
+        pta: usize,
+        level: usize,
+        base: usize,
+        f: &mut dyn FnMut(usize, &mut PageTableEntry),
+    ) {
+        let pt = unsafe { &mut *(pta as *mut PageTable) };
+        let n = if level == 2 { KERNEL_HALF } else { 512 };
+        for i in 0..n {
+            let pte = &mut pt[i];
+            if !pte.is(PTE_V) {
+                continue;
+            }
+            let va = base | i << (PGSHIFT + 9 * level);
+            if level == 0 {
+                f(va, pte);
+            } else {
+                walk_level(pte.va(), level - 1, va, f);
+            }
+        }
+    }
+    walk_level(pta, 2, 0, f);
+}
+
+/// Share the user half of page table `old` with page table `new`.
+/// Writable pages become read-only copy-on-write pages in both of them,
+/// and are copied on the first write, see `cow_fault`.
+/// Pages of shared mappings stay writable in both.
+/// Return None if a page-table page can't be allocated, in which case the
+/// pages shared so far are unmapped from `new` and given back.
+pub fn uvmcopy(old: usize, new: usize) -> Option<()> {
+    let mut copied = true;
+    for_each_user_pte(old, &mut |va, pte| {
+        if !copied {
+            return;
+        }
+        let Some(child) = get_pte(new, va, 2) else {
+            copied = false;
+            return;
+        };
+        if pte.is(PTE_W) && !pte.is(PTE_SHARED) {
+            pte.bits = (pte.bits & !PTE_W) | PTE_COW;
+        }
+        share_page(pte.va());
+        child.bits = pte.bits;
+    });
+    // pages of `old` became read-only
+    shootdown(old, 0, USER_TOP);
+    if !copied {
+        // the page-table pages of `new` are freed with it, see `uvmfree`
+        for_each_user_pte(new, &mut |_, pte| {
+            free_page(pte.va());
+            pte.bits = 0;
+        });
+    }
+    copied.then_some(())
+}
+
+/// Handle a write to a copy-on-write page.
+/// Return Ok(false) if va is not a copy-on-write page,
+/// or fail if there is no memory for the copy.
+pub fn cow_fault(pta: usize, va: usize) -> Result<bool, &'static str> {
+    let pte = match walk(pta, page_down(va)) {
+        Some(pte) if pte.is(PTE_U) && pte.is(PTE_COW) => pte,
+        _ => return Ok(false),
+    };
+    let page = pte.va();
+    let flag = (pte.flags() & !PTE_COW) | PTE_W;
+    if page_refcount(page) == 1 {
+        // the last owner takes the page
+        pte.set_pa(page - PA2VA_OFFSET, flag);
+    } else {
+        let copy = try_alloc_page().ok_or("out of memory")?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(page as *const u8, copy as *mut u8, PGSIZE);
+        }
+        pte.set_pa(copy - PA2VA_OFFSET, flag);
+        free_page(page);
+    }
+    shootdown(pta, va, PGSIZE);
+    Ok(true)
+}
+
+/// Virtual address of the page table in use on the current hart.
+pub fn current_pagetable() -> usize {
+    (riscv::register::satp::read().ppn() << PGSHIFT) + PA2VA_OFFSET
+}
+
+/// Free all user pages and page-table pages of a user page table.
+/// The page table must not be in use.
+pub fn uvmfree(pta: usize) {
+    fn free_level(pta: usize, level: usize) {
+        let pt = unsafe { &mut *(pta as *mut PageTable) };
+        let n = if level == 2 { KERNEL_HALF } else { 512 };
+        for i in 0..n {
+            let pte = &mut pt[i];
+            if pte.is(PTE_V) {
+                // leaves of the last level are user pages
+                if level > 0 {
+                    free_level(pte.va(), level - 1);
+                }
+                free_page(pte.va());
+                pte.bits = 0;
+            }
+        }
+    }
+    free_level(pta, 2);
+    free_page(pta);
+}
+
+/// Copy from kernel to the user virtual address va in a page table.
+/// Return None if part of the destination is not mapped for user.
+pub fn copyout(pta: usize, va: usize, src: &[u8]) -> Option<()> {
+    let mut copied = 0;
+    while copied < src.len() {
+        let va = va + copied;
+        let n = (src.len() - copied).min(PGSIZE - va % PGSIZE);
+        let dst = walkaddr(pta, va)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(src[copied..].as_ptr(), dst as *mut u8, n);
         }
+        copied += n;
     }
+    Some(())
 }
@@ -0,0 +1,307 @@
+//! Virtual memory areas of user address spaces: the image, heap, stack and
+//! mmap regions. Pages are allocated on the first access, see `VmaList::fault`.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use config::{layout::*, vm::*};
+
+use crate::fs::Inode;
+use crate::mm::{free_page, try_alloc_page, vm};
+
+/// A page-aligned region [start, end) of a user address space
+#[derive(Clone)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    /// PTE_R, PTE_W and PTE_X of its pages
+    pub perm: usize,
+    /// shared with forked children instead of copy-on-write
+    pub shared: bool,
+    /// backing file and the file offset of start, None for anonymous memory
+    pub file: Option<(Inode, usize)>,
+}
+
+impl Vma {
+    /// An area of len bytes rounded up to pages, placed by `VmaList::mmap`
+    pub fn new(len: usize, perm: usize, shared: bool, file: Option<(Inode, usize)>) -> Self {
+        Self {
+            start: 0,
+            end: page_up(len),
+            perm,
+            shared,
+            file,
+        }
+    }
+
+    /// The user stack right below USTACKTOP
+    pub fn stack() -> Self {
+        Self {
+            start: MMAP_TOP,
+            end: USTACKTOP,
+            perm: PTE_R | PTE_W,
+            shared: false,
+            file: None,
+        }
+    }
+
+    /// PTE flags of its pages, PTE_U is added when they are mapped
+    pub fn pte_flags(&self) -> usize {
+        if self.shared {
+            self.perm | PTE_SHARED
+        } else {
+            self.perm
+        }
+    }
+
+    /// Initial content of the page at va: zeros, or the file content there.
+    /// The part of the page beyond the end of the file is zeroed.
+    pub fn fill(&self, va: usize, page: usize) {
+        if let Some((inode, offset)) = &self.file {
+            let buf = unsafe { core::slice::from_raw_parts_mut(page as *mut u8, PGSIZE) };
+            inode.read(offset + (va - self.start), buf);
+        }
+    }
+
+    /// Split at va, keeping [start, va) and returning [va, end).
+    fn split(&mut self, va: usize) -> Vma {
+        let mut right = self.clone();
+        right.start = va;
+        if let Some((_, offset)) = &mut right.file {
+            *offset += va - self.start;
+        }
+        self.end = va;
+        right
+    }
+}
+
+/// The areas of an address space, by start address
+#[derive(Clone, Default)]
+pub struct VmaList {
+    areas: BTreeMap<usize, Vma>,
+}
+
+impl VmaList {
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// The area containing va
+    pub fn find(&self, va: usize) -> Option<&Vma> {
+        let (_, vma) = self.areas.range(..=va).next_back()?;
+        (va < vma.end).then_some(vma)
+    }
+
+    /// Whether any area intersects [start, end)
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.areas
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, vma)| vma.end > start)
+    }
+
+    /// The highest free range of size bytes within [bottom, top)
+    fn find_gap(&self, size: usize, bottom: usize, top: usize) -> Option<usize> {
+        let mut top = top;
+        for vma in self.areas.values().rev() {
+            if vma.end <= top && top - vma.end >= size {
+                break;
+            }
+            top = top.min(vma.start);
+        }
+        let start = top.checked_sub(size)?;
+        (start >= bottom).then_some(start)
+    }
+
+    /// Remove [start, end) from the list, splitting the areas crossing its bounds.
+    /// Return the pieces inside the range, in address order.
+    fn carve(&mut self, start: usize, end: usize) -> Vec<Vma> {
+        let keys: Vec<usize> = self
+            .areas
+            .range(..end)
+            .filter(|(_, vma)| vma.end > start)
+            .map(|(&key, _)| key)
+            .collect();
+        let mut inside = Vec::new();
+        for key in keys {
+            let mut vma = self.areas.remove(&key).unwrap();
+            if vma.start < start {
+                let right = vma.split(start);
+                self.areas.insert(vma.start, vma);
+                vma = right;
+            }
+            if vma.end > end {
+                let right = vma.split(end);
+                self.areas.insert(right.start, right);
+            }
+            inside.push(vma);
+        }
+        inside
+    }
+
+    /// Add an area that overlaps no other, without mapping its pages.
+    /// It is merged into the area right before it when both are anonymous
+    /// and alike, so a heap grown by small steps stays one area.
+    pub fn insert(&mut self, vma: Vma) {
+        if let Some((_, prev)) = self.areas.range_mut(..vma.start).next_back() {
+            if prev.end == vma.start
+                && prev.file.is_none()
+                && vma.file.is_none()
+                && prev.perm == vma.perm
+                && prev.shared == vma.shared
+            {
+                prev.end = vma.end;
+                return;
+            }
+        }
+        self.areas.insert(vma.start, vma);
+    }
+
+    /// Handle a page fault at va, access is PTE_R, PTE_W or PTE_X.
+    /// Map the page if the area allows the access, or copy it if it is
+    /// copy-on-write. A page that allows it already was mapped by another
+    /// thread, or its old entry is still cached: the TLB is flushed.
+    /// Return why the access is invalid otherwise.
+    pub fn fault(&self, pta: usize, va: usize, access: usize) -> Result<(), &'static str> {
+        let vma = self.find(va).ok_or("address not mapped")?;
+        if vma.perm & access == 0 {
+            return Err("permission denied");
+        }
+        let va = page_down(va);
+        if vm::walkaddr(pta, va).is_none() {
+            map_page(pta, vma, va).ok_or("out of memory")
+        } else if vm::useraddr(pta, va, access).is_some() {
+            unsafe { riscv::asm::sfence_vma(0, va) };
+            Ok(())
+        } else if access == PTE_W && vm::cow_fault(pta, va)? {
+            Ok(())
+        } else {
+            // the page disagrees with its area
+            Err("permission denied")
+        }
+    }
+
+    /// Map a new area, see `Vma::new`, at addr if it is given, replacing what
+    /// was mapped there, or in a free range within [bottom, MMAP_TOP).
+    /// Pages of private areas are mapped on the first access. Shared areas
+    /// are mapped at once, so processes forked later share all of their pages.
+    /// Return the start address, or None if there is no room or memory runs out.
+    /// The replaced mappings are kept if memory runs out.
+    pub fn mmap(
+        &mut self,
+        pta: usize,
+        addr: Option<usize>,
+        bottom: usize,
+        mut vma: Vma,
+    ) -> Option<usize> {
+        let len = vma.end - vma.start;
+        if len == 0 {
+            return None;
+        }
+        let start = match addr {
+            Some(addr) => {
+                if !addr.is_multiple_of(PGSIZE)
+                    || addr < bottom
+                    || addr.checked_add(len)? > MMAP_TOP
+                {
+                    return None;
+                }
+                addr
+            }
+            None => self.find_gap(len, bottom, MMAP_TOP)?,
+        };
+        vma.start = start;
+        vma.end = start + len;
+        // allocated with their page tables before the old areas go away
+        let pages = if vma.shared {
+            let pages = alloc_pages(&vma)?;
+            if vm::uvmreserve(pta, start, len).is_none() {
+                pages.into_iter().for_each(free_page);
+                return None;
+            }
+            pages
+        } else {
+            Vec::new()
+        };
+        if addr.is_some() {
+            self.munmap(pta, start, len);
+        }
+        if vma.shared {
+            map_pages(pta, &vma, pages);
+        }
+        self.areas.insert(start, vma);
+        Some(start)
+    }
+
+    /// Unmap the areas within [addr, addr + len) and free their pages.
+    pub fn munmap(&mut self, pta: usize, addr: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        for vma in self.carve(addr, addr.saturating_add(page_up(len))) {
+            vm::uvmdealloc(pta, vma.start, vma.end - vma.start);
+        }
+    }
+
+    /// Change the permissions of [addr, addr + len) to perm.
+    /// Return None if part of the range is not mapped, in which case nothing is changed.
+    pub fn mprotect(&mut self, pta: usize, addr: usize, len: usize, perm: usize) -> Option<()> {
+        let end = addr.checked_add(page_up(len))?;
+        let mut pieces = self.carve(addr, end);
+        let mut next = addr;
+        let covered = pieces.iter().all(|vma| {
+            let contiguous = vma.start == next;
+            next = vma.end;
+            contiguous
+        }) && next == end;
+        for vma in pieces.iter_mut() {
+            if covered {
+                vma.perm = perm;
+                vm::uvmprotect(pta, vma.start, vma.end - vma.start, vma.pte_flags());
+            }
+        }
+        for vma in pieces {
+            self.areas.insert(vma.start, vma);
+        }
+        covered.then_some(())
+    }
+}
+
+/// Allocate, fill and map the page of an area at va.
+fn map_page(pta: usize, vma: &Vma, va: usize) -> Option<()> {
+    let page = try_alloc_page()?;
+    vma.fill(va, page);
+    vm::uvmmap(pta, va, page - PA2VA_OFFSET, PGSIZE, vma.pte_flags()).or_else(|| {
+        free_page(page);
+        None
+    })
+}
+
+/// Allocate and fill all pages of a new area, in address order.
+/// When memory runs out, the pages allocated so far are freed.
+fn alloc_pages(vma: &Vma) -> Option<Vec<usize>> {
+    // reserved first, the heap needs frames too
+    let mut pages = Vec::new();
+    pages
+        .try_reserve_exact((vma.end - vma.start) / PGSIZE)
+        .ok()?;
+    for va in (vma.start..vma.end).step_by(PGSIZE) {
+        let Some(page) = try_alloc_page() else {
+            pages.into_iter().for_each(free_page);
+            return None;
+        };
+        vma.fill(va, page);
+        pages.push(page);
+    }
+    Some(pages)
+}
+
+/// Map the pages from `alloc_pages` at their area,
+/// whose page tables are reserved, see `vm::uvmreserve`.
+fn map_pages(pta: usize, vma: &Vma, pages: Vec<usize>) {
+    for (va, page) in (vma.start..vma.end).step_by(PGSIZE).zip(pages) {
+        vm::uvmmap(pta, va, page - PA2VA_OFFSET, PGSIZE, vma.pte_flags())
+            .expect("mmap: page tables not reserved");
+    }
+}
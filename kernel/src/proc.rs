@@ -1,180 +1,777 @@
 use crate::context::{Context, TrapFrame};
-use crate::sync::SpinLock;
-use alloc::vec::Vec;
-use core::arch::asm;
+use crate::cpu::mycpu;
+use crate::fs::{FType, File, FileRef, Inode, Pipe};
+use crate::mm::vma::{Vma, VmaList};
+use crate::mm::{alloc_page, kstack::KernelStack, vm};
+use crate::sched::{Policy, Scheduler};
+use crate::signal::Signals;
+use crate::sync::{Mutex, SpinLock, WaitQueue};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use config::errno::Errno;
+use config::{fs::NOFILE, layout::*, syscall::*, vm::*};
+use core::arch::global_asm;
+
+global_asm!(include_str!("asm/initcode.S"));
 
 lazy_static! {
     pub static ref PROC_MANAGER: SpinLock<Processes> =
         SpinLock::new(Processes::new(), "ProcManagerLock");
 }
 
+/// Processes by pid
+pub type ProcMap = BTreeMap<usize, Box<Process>>;
+
+/// Parents waiting in `waitpid` sleep here
+static CHILD_EXIT: WaitQueue = WaitQueue::new();
+
+/// The first process, which adopts orphans
+pub const INIT_PID: usize = 0;
+
 pub struct Processes {
-    pub procs: Vec<Process>,
-    pub current_pid: usize,
+    /// PCBs are boxed, so contexts stay in place while the map changes
+    pub procs: ProcMap,
+    /// the pid picked last, where round-robin goes on
+    last_pid: usize,
+    next_pid: usize,
+    policy: Policy,
 }
 
+/// Return to user mode for the first time.
+/// The trap frame was prepared by `Process::new`.
 #[no_mangle]
-pub fn loop_print() -> ! {
-    use config::syscall::*;
-    loop {
-        let pid: usize;
-        unsafe {
-            asm!(
-                "ecall",
-                out("a0") pid,
-                in("a7") SYSCALL_GETPID,
-            );
+pub fn forkret() -> ! {
+    extern "C" {
+        fn __restore(ctx: usize) -> !;
+    }
+    // still held by the scheduler, see `sched::scheduler`
+    unsafe { PROC_MANAGER.force_unlock() };
+    let mut pm = PROC_MANAGER.lock();
+    let trapframe = pm.current().trapframe;
+    drop(pm);
+    unsafe { __restore(trapframe) }
+}
+
+/// Duplicate the current process, see `Processes::fork`.
+pub fn fork() -> Result<usize, Errno> {
+    PROC_MANAGER.lock().fork()
+}
+
+/// Make a blocked process ready again.
+/// The process may have been reaped in the meantime.
+pub fn wakeup(pid: usize) {
+    let mut pm = PROC_MANAGER.lock();
+    if let Some(proc) = pm.procs.get_mut(&pid) {
+        if proc.state == ProcState::Blocked {
+            proc.set_state(ProcState::Ready);
         }
-        let string = alloc::format!("Hello from process {}\nSay something: ", pid);
-        unsafe {
-            asm!(
-                "ecall",
-                inlateout("a0") config::std_io::STDOUT => _,
-                in("a1") string.as_ptr(),
-                in("a2") string.len(),
-                in("a7") SYSCALL_WRITE,
-            );
+    }
+}
+
+/// Terminate the current thread with an exit code.
+/// It stays as a zombie until its parent reaps it with `waitpid`,
+/// and its children are handed to init. The address space and the open
+/// files go away with the last thread of the process.
+pub fn exit(code: i32) -> ! {
+    // drop our share of the open files first, closing them may wake up
+    // other processes
+    let files = core::mem::take(&mut PROC_MANAGER.lock().current().files);
+    drop(files);
+    let mut pm = PROC_MANAGER.lock();
+    let pid = pm.current_pid();
+    assert_ne!(pid, INIT_PID, "init exiting");
+    for proc in pm.procs.values_mut() {
+        if proc.parent == Some(pid) {
+            proc.parent = Some(INIT_PID);
         }
-        let mut buf = [0u8; 128];
-        let len: usize;
-        unsafe {
-            asm!(
-                "ecall",
-                inlateout("a0") config::std_io::STDIN => len,
-                in("a1") buf.as_mut_ptr(),
-                in("a2") buf.len(),
-                in("a7") SYSCALL_READ,
-            );
+    }
+    let proc = pm.current();
+    proc.exit_code = code;
+    proc.set_state(ProcState::Exited);
+    // the parent can't reap us before the lock is released by the scheduler,
+    // so our kernel stack is not freed while we are still running on it
+    CHILD_EXIT.wake_all_procs(&mut pm);
+    crate::sched::sched(pm);
+    unreachable!("zombie {} scheduled", pid)
+}
+
+/// Terminate all threads of the current process with an exit code.
+/// The others exit the next time they would return to user mode,
+/// blocked and stopped ones are woken up for that.
+pub fn exit_group(code: i32) -> ! {
+    let mut pm = PROC_MANAGER.lock();
+    let me = pm.current();
+    let (pid, tgid) = (me.pid, me.tgid);
+    for proc in pm.procs.values_mut() {
+        if proc.tgid == tgid && proc.pid != pid && proc.state != ProcState::Exited {
+            proc.killed = Some(code);
+            if matches!(proc.state, ProcState::Blocked | ProcState::Stopped) {
+                proc.set_state(ProcState::Ready);
+            }
         }
-        unsafe {
-            let string = alloc::format!(
-                "You said: {:?}\n",
-                core::str::from_utf8_unchecked(core::slice::from_raw_parts(buf.as_ptr(), len))
-            );
-            asm!(
-                "ecall",
-                inlateout("a0") 1 => _,
-                in("a1") string.as_ptr(),
-                in("a2") string.len(),
-                in("a7") SYSCALL_WRITE,
-            );
+    }
+    drop(pm);
+    exit(code)
+}
+
+/// Wait for a child to exit, pid -1 means any child.
+/// Return the pid and exit code of the reaped child. Fail with ECHILD
+/// if there is no such child, or EINTR if a signal interrupts the wait.
+pub fn waitpid(pid: isize) -> Result<(usize, i32), Errno> {
+    loop {
+        let mut pm = PROC_MANAGER.lock();
+        if let Some(child) = pm.find_child(pid).ok_or(Errno::ECHILD)? {
+            // dropping the PCB frees its kernel stack and address space
+            let proc = pm.procs.remove(&child).unwrap();
+            return Ok((child, proc.exit_code));
         }
-        // exit
-        unsafe {
-            asm!(
-                "ecall",
-                inlateout("a0") 0 => _,
-                in("a7") SYSCALL_EXIT,
-            );
+        if pm.current().interrupted() {
+            return Err(Errno::EINTR);
         }
+        CHILD_EXIT.sleep_procs(pm);
     }
 }
 
-#[no_mangle]
-pub fn forkret() -> ! {
-    riscv::register::sepc::write(loop_print as usize);
-    sret!();
-    unreachable!()
+/// Set the nice value of a process, pid 0 means the caller.
+/// Lower values mean higher priority, clamped to [NICE_MIN, NICE_MAX].
+/// Fail with ESRCH if there is no such process.
+pub fn setpriority(pid: usize, nice: i32) -> Result<(), Errno> {
+    let mut pm = PROC_MANAGER.lock();
+    let pid = if pid == 0 { pm.current_pid() } else { pid };
+    let proc = pm.procs.get_mut(&pid).ok_or(Errno::ESRCH)?;
+    proc.nice = nice.clamp(NICE_MIN, NICE_MAX);
+    Ok(())
 }
 
-/// Spawn proc 0
-/// - We don't need to & must not store any context here
-/// as the current context belongs to the kernel main thread.
-/// - The kernel thread should never involve in context switching.
-pub fn init() -> ! {
+/// Log the syscalls of the current process from now on if on, or stop.
+/// Its threads, children and programs it execs are traced too.
+pub fn trace(on: bool) {
     let mut pm = PROC_MANAGER.lock();
-    pm.init();
-    let sp = pm.procs[0].context.sp;
-    let ra = pm.procs[0].context.ra;
+    let tgid = pm.current().tgid;
+    for proc in pm.procs.values_mut().filter(|proc| proc.tgid == tgid) {
+        proc.trace = on;
+    }
+}
+
+/// Replace the address space of the current thread with an ELF file,
+/// and set up the trap frame to run it with arguments argv.
+/// Other threads keep running in the old address space.
+/// Return argc. On failure nothing is changed: ENOENT if there is no
+/// such file, EACCES if it is not a regular file, E2BIG for too many
+/// arguments or if they don't fit on the stack, ENOMEM if memory runs out
+/// for the stack, or ENOEXEC if it can't be loaded.
+pub fn exec(path: &str, argv: &[&str], tf: &mut TrapFrame) -> Result<usize, Errno> {
+    let inode = crate::fs::namei(path).ok_or(Errno::ENOENT)?;
+    if inode.dinode.typ != FType::File {
+        return Err(Errno::EACCES);
+    }
+    if argv.len() > MAXARG {
+        return Err(Errno::E2BIG);
+    }
+    // freed on failure when dropped
+    let mut mm = AddrSpace::new();
+    let (entry, sp, end) = load_image(&inode, mm.pagetable, &mut mm.vmas, argv)?;
+    mm.heap_start = end;
+    mm.brk = end;
+    let pagetable = mm.pagetable;
+    let mut pm = PROC_MANAGER.lock();
+    let proc = pm.current();
+    proc.pagetable = pagetable;
+    proc.signals.reset();
+    let old = core::mem::replace(&mut proc.mm, Arc::new(SpinLock::new(mm, "AddrSpaceLock")));
+    vm::activate(pagetable);
     drop(pm);
-    write_reg!(sp, sp);
-    write_reg!(ra, ra);
-    ret!();
-    unreachable!()
+    drop(old);
+    // main(argc, argv)
+    *tf = TrapFrame::user(entry, sp);
+    tf.regs[SYSCALL_REG_ARG1] = sp;
+    Ok(argv.len())
+}
+
+/// Load an ELF file into a new page table and build the user stack,
+/// recording their areas in vmas. Return the entry point,
+/// the stack pointer, which points to argv, and the end of the image.
+/// Fail with E2BIG if the arguments don't fit on the stack, see `exec`.
+fn load_image(
+    inode: &Inode,
+    pta: usize,
+    vmas: &mut VmaList,
+    argv: &[&str],
+) -> Result<(usize, usize, usize), Errno> {
+    let (entry, end) = crate::loader::load(inode, pta, vmas).ok_or(Errno::ENOEXEC)?;
+    // mapped now, as the arguments are copied there
+    let bottom = USTACKTOP - USTACKSIZE;
+    vm::uvmalloc(pta, bottom, USTACKSIZE, PTE_R | PTE_W).ok_or(Errno::ENOMEM)?;
+    vmas.insert(Vma::stack());
+    // push argument strings, then the null-terminated argv array.
+    // riscv requires sp to be 16-byte aligned.
+    let mut sp = USTACKTOP;
+    let mut ustack = [0usize; MAXARG + 1];
+    for (i, arg) in argv.iter().enumerate() {
+        sp = (sp - arg.len() - 1) & !0xf;
+        if sp < bottom {
+            return Err(Errno::E2BIG);
+        }
+        vm::copyout(pta, sp, arg.as_bytes()).ok_or(Errno::EFAULT)?;
+        vm::copyout(pta, sp + arg.len(), &[0]).ok_or(Errno::EFAULT)?;
+        ustack[i] = sp;
+    }
+    let size = (argv.len() + 1) * core::mem::size_of::<usize>();
+    sp = (sp - size) & !0xf;
+    if sp < bottom {
+        return Err(Errno::E2BIG);
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(ustack.as_ptr() as *const u8, size) };
+    vm::copyout(pta, sp, bytes).ok_or(Errno::EFAULT)?;
+    Ok((entry, sp, end))
+}
+
+/// Move the program break of the current process by increment bytes.
+/// The heap area grows without mapping pages, they are zero-filled on the
+/// first access. Pages are freed when it shrinks.
+/// Return the old break. Fail with ENOMEM if it would leave
+/// [heap_start, stack) or run into a mapping, in which case nothing is changed.
+pub fn sbrk(increment: isize) -> Result<usize, Errno> {
+    let mut pm = PROC_MANAGER.lock();
+    let mut mm = pm.current().mm.lock();
+    let old = mm.brk;
+    let new = old.checked_add_signed(increment).ok_or(Errno::ENOMEM)?;
+    if new < mm.heap_start || new > MMAP_TOP {
+        return Err(Errno::ENOMEM);
+    }
+    let (start, end) = (page_up(old), page_up(new));
+    if start < end {
+        if mm.vmas.overlaps(start, end) {
+            return Err(Errno::ENOMEM);
+        }
+        mm.vmas.insert(Vma {
+            start,
+            end,
+            perm: PTE_R | PTE_W,
+            shared: false,
+            file: None,
+        });
+    } else {
+        let pagetable = mm.pagetable;
+        mm.vmas.munmap(pagetable, end, start - end);
+    }
+    mm.brk = new;
+    Ok(old)
+}
+
+/// Map a new area into the current process, see `VmaList::mmap`.
+/// Mappings stay above the heap. Fail with ENOMEM if there is no room.
+pub fn mmap(addr: Option<usize>, vma: Vma) -> Result<usize, Errno> {
+    let mut pm = PROC_MANAGER.lock();
+    let mut mm = pm.current().mm.lock();
+    let (pagetable, bottom) = (mm.pagetable, page_up(mm.brk));
+    mm.vmas
+        .mmap(pagetable, addr, bottom, vma)
+        .ok_or(Errno::ENOMEM)
+}
+
+/// Unmap [addr, addr + len) from the current process.
+pub fn munmap(addr: usize, len: usize) -> Result<(), Errno> {
+    if !addr.is_multiple_of(PGSIZE) {
+        return Err(Errno::EINVAL);
+    }
+    let mut pm = PROC_MANAGER.lock();
+    let mut mm = pm.current().mm.lock();
+    let pagetable = mm.pagetable;
+    mm.vmas.munmap(pagetable, addr, len);
+    Ok(())
+}
+
+/// Change the permissions of [addr, addr + len) in the current process.
+/// Fail with ENOMEM if part of it is not mapped.
+pub fn mprotect(addr: usize, len: usize, perm: usize) -> Result<(), Errno> {
+    if !addr.is_multiple_of(PGSIZE) {
+        return Err(Errno::EINVAL);
+    }
+    let mut pm = PROC_MANAGER.lock();
+    let mut mm = pm.current().mm.lock();
+    let pagetable = mm.pagetable;
+    mm.vmas
+        .mprotect(pagetable, addr, len, perm)
+        .ok_or(Errno::ENOMEM)
+}
+
+/// Handle a page fault of the current process at a user address,
+/// see `VmaList::fault`.
+pub fn page_fault(va: usize, access: usize) -> Result<(), &'static str> {
+    let mut pm = PROC_MANAGER.lock();
+    let mm = pm.current().mm.lock();
+    mm.vmas.fault(mm.pagetable, va, access)
+}
+
+/// The open files of the current process
+fn files() -> Arc<FdTable> {
+    PROC_MANAGER.lock().current().files.clone()
+}
+
+/// Open the file at path for the current process, see `File::open`.
+/// Return the lowest free fd, or fail with EMFILE if there is none.
+pub fn open(path: &str, flags: usize) -> Result<usize, Errno> {
+    let file = File::open(path, flags)?;
+    files()
+        .alloc(Arc::new(Mutex::new(file)))
+        .ok_or(Errno::EMFILE)
+}
+
+/// The open file behind fd in the current process, EBADF if it is not open
+pub fn getfile(fd: usize) -> Result<FileRef, Errno> {
+    files().get(fd).ok_or(Errno::EBADF)
+}
+
+/// Release fd of the current process.
+/// The file is closed when no descriptor refers to it anymore.
+pub fn close(fd: usize) -> Result<(), Errno> {
+    files().take(fd).map(drop).ok_or(Errno::EBADF)
+}
+
+/// Make the lowest free fd of the current process refer to the file of fd.
+pub fn dup(fd: usize) -> Result<usize, Errno> {
+    let files = files();
+    files
+        .alloc(files.get(fd).ok_or(Errno::EBADF)?)
+        .ok_or(Errno::EMFILE)
+}
+
+/// Make newfd refer to the file of oldfd, closing what newfd referred to.
+/// Return newfd.
+pub fn dup2(oldfd: usize, newfd: usize) -> Result<usize, Errno> {
+    let files = files();
+    let file = files.get(oldfd).ok_or(Errno::EBADF)?;
+    let old = files.replace(newfd, file).ok_or(Errno::EBADF)?;
+    drop(old);
+    Ok(newfd)
+}
+
+/// Create a pipe in the current process.
+/// Return the fds of its read end and write end.
+pub fn pipe() -> Result<(usize, usize), Errno> {
+    let pipe = Arc::new(Pipe::new());
+    let rf = Arc::new(Mutex::new(File::Pipe {
+        pipe: pipe.clone(),
+        writable: false,
+    }));
+    let wf = Arc::new(Mutex::new(File::Pipe {
+        pipe,
+        writable: true,
+    }));
+    let files = files();
+    let rfd = files.alloc(rf).ok_or(Errno::EMFILE)?;
+    let Some(wfd) = files.alloc(wf) else {
+        files.take(rfd);
+        return Err(Errno::EMFILE);
+    };
+    Ok((rfd, wfd))
+}
+
+/// Start a thread of the current process at entry, with stack pointer sp
+/// and arg in a0. It shares the address space and the open files, and is
+/// a child of the caller, which reaps it with `waitpid`.
+/// Return its pid, or fail with EAGAIN if there is no kernel stack for it.
+pub fn clone(entry: usize, sp: usize, arg: usize) -> Result<usize, Errno> {
+    let mut pm = PROC_MANAGER.lock();
+    let pid = pm.alloc_pid();
+    let parent = pm.current();
+    let thread = Process::new(pid, parent.mm.clone(), parent.files.clone());
+    let mut thread = Box::new(thread.ok_or(Errno::EAGAIN)?);
+    thread.parent = Some(parent.pid);
+    thread.tgid = parent.tgid;
+    thread.nice = parent.nice;
+    thread.signals = parent.signals.inherit();
+    thread.trace = parent.trace;
+    *thread.trapframe() = TrapFrame::user(entry, sp);
+    thread.trapframe().regs[SYSCALL_REG_ARG0] = arg;
+    pm.procs.insert(pid, thread);
+    Ok(pid)
+}
+
+/// Spawn proc 0, then turn the kernel main thread into the scheduler.
+pub fn init() -> ! {
+    PROC_MANAGER.lock().init();
+    crate::sched::scheduler()
 }
 
 impl Processes {
     pub const fn new() -> Self {
         Self {
-            procs: Vec::new(),
-            current_pid: 0,
+            procs: BTreeMap::new(),
+            last_pid: INIT_PID,
+            next_pid: INIT_PID,
+            policy: Policy::new(),
         }
     }
 
     pub fn init(&mut self) {
-        for _ in 0..5 {
-            self.create_task();
-        }
+        self.create_task();
     }
 
     pub fn create_task(&mut self) -> &mut Process {
-        let pid = self.procs.len();
-        let proc = Process::new(pid);
-        self.procs.push(proc);
-        &mut self.procs[pid]
-    }
-
-    pub fn switch_task(&mut self) -> (usize, usize) {
-        let current_pid = self.current_pid;
-        let next_pid = (current_pid + 1) % self.procs.len();
-        let ctx_new: usize;
-        let ctx_old: usize;
-        {
-            let next_task = &mut self.procs[next_pid];
-            next_task.set_state(ProcState::Running);
-            ctx_new = &next_task.context as *const Context as usize;
+        let pid = self.alloc_pid();
+        let mm = Arc::new(SpinLock::new(AddrSpace::new(), "AddrSpaceLock"));
+        let proc = Process::new(pid, mm, Arc::default()).expect("out of kernel stacks");
+        let mut proc = Box::new(proc);
+        // "strace" on the kernel command line traces everything from init on
+        proc.trace = crate::dtb::machine().has_option("strace");
+        proc.load_initcode();
+        self.procs.entry(pid).or_insert(proc)
+    }
+
+    /// Duplicate the current thread into a new process, return its pid.
+    /// The child shares the address space copy-on-write and
+    /// returns 0 from the syscall. Fail with ENOMEM if its page table can't
+    /// be allocated, or EAGAIN if there is no kernel stack for it.
+    pub fn fork(&mut self) -> Result<usize, Errno> {
+        let pid = self.alloc_pid();
+        let parent = self.current();
+        let mm = parent.mm.lock().copy().ok_or(Errno::ENOMEM)?;
+        let mm = Arc::new(SpinLock::new(mm, "AddrSpaceLock"));
+        let files = Arc::new(parent.files.copy());
+        let mut child = Box::new(Process::new(pid, mm, files).ok_or(Errno::EAGAIN)?);
+        child.parent = Some(parent.pid);
+        child.nice = parent.nice;
+        child.signals = parent.signals.inherit();
+        child.trace = parent.trace;
+        *child.trapframe() = *parent.trapframe();
+        child.trapframe().regs[SYSCALL_REG_RET] = 0;
+        self.procs.insert(pid, child);
+        Ok(pid)
+    }
+
+    /// Look for a child of the current process matching pid, -1 means any.
+    /// Return None if there is no such child, Some(None) if they are all alive,
+    /// or the pid of a zombie.
+    fn find_child(&self, pid: isize) -> Option<Option<usize>> {
+        let mut found = false;
+        let me = self.current_pid();
+        for proc in self.procs.values() {
+            if proc.parent == Some(me) && (pid == -1 || proc.pid == pid as usize) {
+                if proc.state == ProcState::Exited {
+                    return Some(Some(proc.pid));
+                }
+                found = true;
+            }
         }
-        {
-            let current_task = &mut self.procs[current_pid];
+        found.then_some(None)
+    }
+
+    fn alloc_pid(&mut self) -> usize {
+        let pid = self.next_pid;
+        self.next_pid += 1;
+        pid
+    }
+
+    /// The pid of the process running on this hart
+    pub fn current_pid(&self) -> usize {
+        mycpu().proc.expect("no process running on this hart")
+    }
+
+    /// The process running on this hart
+    pub fn current(&mut self) -> &mut Process {
+        let pid = self.current_pid();
+        self.procs.get_mut(&pid).unwrap()
+    }
+
+    /// Mark the current process blocked and return its pid. It stops being
+    /// scheduled once it switches to the scheduler, until `wakeup` is called.
+    pub fn block_current(&mut self) -> usize {
+        self.current().set_state(ProcState::Blocked);
+        self.current_pid()
+    }
+
+    /// Let the scheduling policy pick the next ready process and make it
+    /// current on this hart. Processes running on other harts are not ready.
+    /// Return the contexts to switch from the scheduler to it,
+    /// or None if every process is running, blocked or exited.
+    pub fn switch_task(&mut self) -> Option<(usize, usize)> {
+        let next_pid = self.policy.pick(&self.procs, self.last_pid)?;
+        let next_task = self.procs.get_mut(&next_pid).unwrap();
+        next_task.set_state(ProcState::Running);
+        next_task.ticks_left = self.policy.quantum(next_task);
+        vm::flush(next_task.pagetable);
+        let ctx_new = &next_task.context as *const Context as usize;
+        let cpu = mycpu();
+        let ctx_old = &mut cpu.scheduler as *mut Context as usize;
+        cpu.proc = Some(next_pid);
+        self.last_pid = next_pid;
+        Some((ctx_old, ctx_new))
+    }
+
+    /// Leave the current process for the scheduler.
+    /// A running process becomes ready, blocked and exited ones stay as they are.
+    pub fn yield_task(&mut self) -> (usize, usize) {
+        let cpu = mycpu();
+        let pid = cpu.proc.take().expect("no process running on this hart");
+        let current_task = self.procs.get_mut(&pid).unwrap();
+        self.policy
+            .leave(current_task, current_task.ticks_left == 0);
+        if current_task.state == ProcState::Running {
             current_task.set_state(ProcState::Ready);
-            ctx_old = &current_task.context as *const Context as usize;
         }
-        self.current_pid = next_pid;
+        let ctx_old = &mut current_task.context as *mut Context as usize;
+        let ctx_new = &cpu.scheduler as *const Context as usize;
         (ctx_old, ctx_new)
     }
+
+    /// Charge a timer tick to the current process.
+    /// Return whether its quantum is used up.
+    pub fn tick(&mut self) -> bool {
+        let current_task = self.current();
+        current_task.ticks_left = current_task.ticks_left.saturating_sub(1);
+        let expired = current_task.ticks_left == 0;
+        self.policy.tick(&mut self.procs);
+        expired
+    }
 }
 
-#[derive(Default)]
+impl Default for Processes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum ProcState {
     Running,
     #[default]
     Ready,
     Blocked,
+    /// by a signal, until SIGCONT
+    Stopped,
     Exited,
 }
 
 /// Process control block
-/// Tasks run in user mode, but use kernel memory for now 
-/// because we don't have virtual memory yet.
+/// Tasks run in user mode, each in its own address space.
 #[rustfmt::skip]
 #[repr(align(4096))]
 pub struct Process {
     /// process id
     pub pid:            usize,
+    /// pid of the parent, None for init
+    pub parent:         Option<usize>,
     /// task state
     pub state:          ProcState,
     /// kernel stack
-    pub kstack:         usize,
+    pub kstack:         KernelStack,
+    /// pid of the first thread of the process, the one fork created
+    pub tgid:           usize,
+    /// root page table of mm, kept here for switching to it
+    pub pagetable:      usize,
+    /// user address space, shared by the threads
+    pub mm:             Arc<SpinLock<AddrSpace>>,
     pub context:        Context,
-    pub trapframe:      TrapFrame,
+    /// timer ticks left in the current quantum
+    pub ticks_left:     usize,
+    /// static priority, lower is more favored
+    pub nice:           i32,
+    /// queue level for the multi-level feedback queue
+    pub level:          usize,
+    /// user registers, saved at the top of the kernel stack
+    pub trapframe:      usize,
+    /// exit code kept for the parent while the process is a zombie
+    pub exit_code:      i32,
+    /// exit code of a thread killed by `exit_group`
+    pub killed:         Option<i32>,
+    /// pending signals and their actions
+    pub signals:        Signals,
+    /// open files, shared by the threads
+    pub files:          Arc<FdTable>,
+    /// log its syscalls, see `trace`
+    pub trace:          bool,
 }
 
 impl Process {
-    pub fn new(pid: usize) -> Self {
+    /// Allocate a process running in mm with the open files.
+    /// It returns to user mode with its trap frame when first scheduled.
+    /// Return None if no kernel stack can be allocated.
+    pub fn new(pid: usize, mm: Arc<SpinLock<AddrSpace>>, files: Arc<FdTable>) -> Option<Self> {
+        let kstack = KernelStack::new()?;
+        let trapframe = kstack.top() - core::mem::size_of::<TrapFrame>();
+        let pagetable = mm.lock().pagetable;
         let mut proc = Self {
             pid,
+            parent: None,
             state: ProcState::default(),
-            kstack: 0,
+            kstack,
+            tgid: pid,
+            pagetable,
+            mm,
             context: Context::default(),
-            trapframe: TrapFrame::default(),
+            ticks_left: 0,
+            nice: 0,
+            level: 0,
+            trapframe,
+            exit_code: 0,
+            killed: None,
+            signals: Signals::new(),
+            files,
+            trace: false,
         };
-        proc.kstack = &proc as *const Process as usize + 4096;
-        proc.context.sp = proc.kstack;
-        proc.context.ra = forkret as usize;
-        proc
+        proc.context.sp = proc.trapframe;
+        proc.context.ra = forkret as *const () as usize;
+        Some(proc)
+    }
+
+    /// Map initcode at address 0 and a user stack, and start from there.
+    /// initcode executes /init.
+    fn load_initcode(&mut self) {
+        extern "C" {
+            fn initcode_start();
+            fn initcode_end();
+        }
+        let code = alloc_page();
+        let len = initcode_end as *const () as usize - initcode_start as *const () as usize;
+        assert!(len <= PGSIZE, "initcode too large");
+        unsafe {
+            core::ptr::copy_nonoverlapping(initcode_start as *const u8, code as *mut u8, len);
+        }
+        let mut mm = self.mm.lock();
+        vm::uvmmap(mm.pagetable, 0, code - PA2VA_OFFSET, PGSIZE, PTE_R | PTE_X)
+            .expect("initcode: out of memory");
+        mm.vmas.insert(Vma {
+            start: 0,
+            end: PGSIZE,
+            perm: PTE_R | PTE_X,
+            shared: false,
+            file: None,
+        });
+        mm.heap_start = PGSIZE;
+        mm.brk = PGSIZE;
+        // user stack
+        vm::uvmalloc(
+            mm.pagetable,
+            USTACKTOP - USTACKSIZE,
+            USTACKSIZE,
+            PTE_R | PTE_W,
+        )
+        .expect("initcode: out of memory");
+        mm.vmas.insert(Vma::stack());
+        drop(mm);
+        *self.trapframe() = TrapFrame::user(0, USTACKTOP);
+        // stdin, stdout and stderr, inherited by all processes.
+        // stdin is a file of its own, so that its readers and the writers
+        // of stdout don't share a lock.
+        self.files.alloc(Arc::new(Mutex::new(File::Console)));
+        let stdout = Arc::new(Mutex::new(File::Console));
+        for _ in 0..2 {
+            self.files.alloc(stdout.clone());
+        }
+    }
+
+    /// The user registers saved on the kernel stack
+    pub fn trapframe(&mut self) -> &mut TrapFrame {
+        unsafe { &mut *(self.trapframe as *mut TrapFrame) }
+    }
+
+    /// Whether a signal or `exit_group` should cut a sleep short,
+    /// see `signal::interrupted`
+    pub fn interrupted(&self) -> bool {
+        self.killed.is_some() || self.signals.deliverable()
     }
 
     pub fn set_state(&mut self, state: ProcState) {
         self.state = state;
     }
 }
+
+/// User address space of a process
+#[rustfmt::skip]
+pub struct AddrSpace {
+    /// root page table
+    pub pagetable:  usize,
+    /// memory areas: the image, the stack, the heap and mappings
+    pub vmas:       VmaList,
+    /// start of the heap, right after the loaded image
+    pub heap_start: usize,
+    /// program break, the end of the heap
+    pub brk:        usize,
+}
+
+impl AddrSpace {
+    /// An empty address space
+    pub fn new() -> Self {
+        Self {
+            pagetable: vm::uvmcreate(),
+            vmas: VmaList::new(),
+            heap_start: 0,
+            brk: 0,
+        }
+    }
+
+    /// A copy sharing the pages copy-on-write, see `vm::uvmcopy`.
+    /// Return None if its page table can't be allocated.
+    pub fn copy(&self) -> Option<Self> {
+        let mm = Self {
+            pagetable: vm::uvmcreate(),
+            vmas: self.vmas.clone(),
+            heap_start: self.heap_start,
+            brk: self.brk,
+        };
+        // freed when dropped on failure
+        vm::uvmcopy(self.pagetable, mm.pagetable)?;
+        Some(mm)
+    }
+}
+
+impl Default for AddrSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for AddrSpace {
+    /// Free the pages once the last thread is reaped.
+    fn drop(&mut self) {
+        vm::uvmfree(self.pagetable);
+    }
+}
+
+/// Open files of a process by fd, shared by its threads
+pub struct FdTable(SpinLock<[Option<FileRef>; NOFILE]>);
+
+impl FdTable {
+    pub fn new() -> Self {
+        Self(SpinLock::new(Default::default(), "FdTableLock"))
+    }
+
+    /// The file behind fd
+    pub fn get(&self, fd: usize) -> Option<FileRef> {
+        self.0.lock().get(fd)?.clone()
+    }
+
+    /// Install file in the lowest free fd and return it.
+    /// Return None if all are in use.
+    pub fn alloc(&self, file: FileRef) -> Option<usize> {
+        let mut files = self.0.lock();
+        let fd = files.iter().position(Option::is_none)?;
+        files[fd] = Some(file);
+        Some(fd)
+    }
+
+    /// Free fd, returning its file to be dropped without the lock held
+    pub fn take(&self, fd: usize) -> Option<FileRef> {
+        self.0.lock().get_mut(fd)?.take()
+    }
+
+    /// Install file in fd, returning what was there.
+    /// Return None if fd is out of range.
+    pub fn replace(&self, fd: usize, file: FileRef) -> Option<Option<FileRef>> {
+        Some(self.0.lock().get_mut(fd)?.replace(file))
+    }
+
+    /// A table with the same open files, for fork
+    pub fn copy(&self) -> Self {
+        Self(SpinLock::new(self.0.lock().clone(), "FdTableLock"))
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
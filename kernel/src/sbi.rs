@@ -37,6 +37,20 @@ pub fn shutdown() -> ! {
     panic!("It should shutdown!")
 }
 
+/// Start a stopped hart at the physical address start_addr in S mode,
+/// with a0 = hartid and a1 = opaque. Return the SBI error code, 0 on success.
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> usize {
+    sbi_call(HSM_EXTENSION, SBI_HART_START, hartid, start_addr, opaque)
+}
+
+/// Flush the TLB entries of [start, start + size) on the harts in the
+/// bit mask hart_mask, and return once they are done.
+pub fn remote_sfence_vma(hart_mask: usize, start: usize, size: usize) {
+    // the legacy call takes the address of the mask
+    let mask = &hart_mask as *const usize as usize;
+    sbi_call(SBI_REMOTE_SFENCE_VMA, 0, mask, start, size);
+}
+
 pub fn set_timer(time: usize) {
     sbi_call(SBI_SET_TIMER, 0, time, 0, 0);
 }
@@ -1,14 +1,181 @@
-use crate::proc::PROC_MANAGER;
+use crate::cpu::mycpu;
+use crate::proc::{ProcMap, ProcState, Process, Processes, PROC_MANAGER};
+use crate::sync::Guard;
 
+extern "C" {
+    fn swtch(old: usize, new: usize);
+}
+
+/// Timer ticks a process may run before it is preempted
+pub const QUANTUM: usize = 5;
+
+/// Scheduler loop of a hart, running on its boot stack.
+/// Pick the next runnable process and switch to it, it switches back
+/// here in `sched`. Wait for an interrupt if nothing is runnable.
+pub fn scheduler() -> ! {
+    loop {
+        // devices may wake processes up while we are looking for one
+        crate::trap::intr_on();
+        let mut pm = PROC_MANAGER.lock();
+        match pm.switch_task() {
+            Some((old, new)) => {
+                // The lock is held across the switch, so no other hart picks the
+                // process before its context is saved. The process releases it.
+                core::mem::forget(pm);
+                unsafe { swtch(old, new) };
+                // the page table of the process may be freed once we unlock
+                crate::mm::vm::use_kernel_pagetable();
+                unsafe { PROC_MANAGER.force_unlock() };
+            }
+            None => {
+                drop(pm);
+                unsafe { riscv::asm::wfi() };
+            }
+        }
+    }
+}
+
+/// Give up the CPU and switch to the scheduler.
+/// The current process runs again later unless it is blocked or exited.
 pub fn schedule() {
-    extern "C" {
-        fn swtch(old: usize, new: usize);
+    sched(PROC_MANAGER.lock());
+}
+
+/// Switch to the scheduler with the process table locked by the caller,
+/// e.g. right after blocking. Return with the lock released.
+pub fn sched(mut pm: Guard<Processes>) {
+    let (old, new) = pm.yield_task();
+    // intena belongs to this kernel thread rather than to the hart
+    let intena = mycpu().intena;
+    core::mem::forget(pm);
+    unsafe { swtch(old, new) };
+    // maybe on another hart, which locked the table before switching to us
+    mycpu().intena = intena;
+    unsafe { PROC_MANAGER.force_unlock() };
+}
+
+/// Charge a timer tick to the current process,
+/// and preempt it once its quantum is used up.
+pub fn tick() {
+    let expired = PROC_MANAGER.lock().tick();
+    if expired {
+        schedule();
+    }
+}
+
+/// A scheduling policy, deciding which process runs next and for how long.
+/// The policy is chosen at build time with the `sched-*` features.
+pub trait Scheduler {
+    /// Choose the next process among the ready ones, None if there is none.
+    /// last is the pid that ran most recently.
+    fn pick(&mut self, procs: &ProcMap, last: usize) -> Option<usize>;
+
+    /// Timer ticks given to a process each time it is picked
+    fn quantum(&self, _proc: &Process) -> usize {
+        QUANTUM
+    }
+
+    /// The process leaves the CPU, expired tells whether its quantum is used up.
+    fn leave(&mut self, _proc: &mut Process, _expired: bool) {}
+
+    /// Called on every timer tick charged to a process.
+    fn tick(&mut self, _procs: &mut ProcMap) {}
+}
+
+#[cfg(feature = "sched-mlfq")]
+pub type Policy = Mlfq;
+#[cfg(all(feature = "sched-priority", not(feature = "sched-mlfq")))]
+pub type Policy = Priority;
+#[cfg(not(any(feature = "sched-priority", feature = "sched-mlfq")))]
+pub type Policy = RoundRobin;
+
+/// Ready processes, starting from the one after last and wrapping around
+fn ready_after(procs: &ProcMap, last: usize) -> impl Iterator<Item = &Process> {
+    procs
+        .range(last + 1..)
+        .chain(procs.range(..=last))
+        .map(|(_, proc)| &**proc)
+        .filter(|proc| proc.state == ProcState::Ready)
+}
+
+/// Every ready process runs in turn
+#[derive(Default)]
+pub struct RoundRobin;
+
+impl RoundRobin {
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+impl Scheduler for RoundRobin {
+    fn pick(&mut self, procs: &ProcMap, last: usize) -> Option<usize> {
+        ready_after(procs, last).next().map(|proc| proc.pid)
+    }
+}
+
+/// Static priority: the ready process with the lowest nice value runs,
+/// round-robin among equals. Lower priorities starve while higher ones are ready.
+#[derive(Default)]
+pub struct Priority;
+
+impl Priority {
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+impl Scheduler for Priority {
+    fn pick(&mut self, procs: &ProcMap, last: usize) -> Option<usize> {
+        // min_by_key keeps the first of equal elements
+        ready_after(procs, last)
+            .min_by_key(|proc| proc.nice)
+            .map(|proc| proc.pid)
+    }
+}
+
+/// Number of queues of the multi-level feedback queue
+pub const MLFQ_LEVELS: usize = 3;
+/// Ticks between two priority boosts, which keep long jobs from starving
+pub const MLFQ_BOOST: usize = 100;
+
+/// Multi-level feedback queue: processes start at level 0 and drop one level
+/// each time they use up their quantum, which doubles at every level.
+/// Processes that give up the CPU early keep their level. Nice values are ignored.
+#[derive(Default)]
+pub struct Mlfq {
+    ticks: usize,
+}
+
+impl Mlfq {
+    pub const fn new() -> Self {
+        Self { ticks: 0 }
+    }
+}
+
+impl Scheduler for Mlfq {
+    fn pick(&mut self, procs: &ProcMap, last: usize) -> Option<usize> {
+        ready_after(procs, last)
+            .min_by_key(|proc| proc.level)
+            .map(|proc| proc.pid)
+    }
+
+    fn quantum(&self, proc: &Process) -> usize {
+        QUANTUM << proc.level
+    }
+
+    fn leave(&mut self, proc: &mut Process, expired: bool) {
+        if expired && proc.level + 1 < MLFQ_LEVELS {
+            proc.level += 1;
+        }
     }
 
-    let mut pm = PROC_MANAGER.lock();
-    let (old, new) = pm.switch_task();
-    drop(pm);
-    unsafe {
-        swtch(old, new);
+    fn tick(&mut self, procs: &mut ProcMap) {
+        self.ticks += 1;
+        if self.ticks.is_multiple_of(MLFQ_BOOST) {
+            for proc in procs.values_mut() {
+                proc.level = 0;
+            }
+        }
     }
 }
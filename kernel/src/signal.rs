@@ -0,0 +1,241 @@
+//! Signals: asynchronous notifications sent with `kill`, and delivered when
+//! the target returns to user mode, see `deliver`. A user handler runs on
+//! the interrupted stack and returns through a trampoline calling
+//! sigreturn, which resumes the interrupted code.
+
+use config::errno::Errno;
+use config::signal::*;
+
+use crate::context::{TrapFrame, REG_A0, REG_RA};
+use crate::proc::{ProcState, Process, INIT_PID, PROC_MANAGER};
+
+/// What a thread does with a signal
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SigAction {
+    /// SIG_DFL, SIG_IGN, or the address of a user function taking the signal
+    pub handler: usize,
+    /// where the handler returns to, a user function calling sigreturn
+    pub restorer: usize,
+}
+
+#[derive(PartialEq, Eq)]
+enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+fn default_action(sig: usize) -> DefaultAction {
+    match sig {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGCONT => DefaultAction::Continue,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// Signal state of a thread
+#[derive(Clone)]
+pub struct Signals {
+    /// sent but not delivered yet, bit n for signal n
+    pending: u32,
+    actions: [SigAction; NSIG],
+    /// user registers while a handler runs, restored by sigreturn.
+    /// Signals with handlers wait until it returns.
+    saved: Option<TrapFrame>,
+}
+
+impl Signals {
+    pub fn new() -> Self {
+        Self {
+            pending: 0,
+            actions: [SigAction::default(); NSIG],
+            saved: None,
+        }
+    }
+
+    /// The state of a new thread or a forked child:
+    /// the same actions, nothing pending and no handler running.
+    pub fn inherit(&self) -> Self {
+        Self {
+            pending: 0,
+            actions: self.actions,
+            saved: None,
+        }
+    }
+
+    /// Reset handlers to the default action on exec,
+    /// as the program installing them is gone. Ignored signals stay ignored.
+    pub fn reset(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+        self.saved = None;
+    }
+
+    fn ignored(&self, sig: usize) -> bool {
+        match self.actions[sig].handler {
+            SIG_IGN => true,
+            SIG_DFL => matches!(
+                default_action(sig),
+                DefaultAction::Ignore | DefaultAction::Continue
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether a signal can be delivered now
+    pub fn deliverable(&self) -> bool {
+        self.next().is_some()
+    }
+
+    /// The lowest pending signal that can be delivered now
+    fn next(&self) -> Option<usize> {
+        (1..NSIG).find(|&sig| {
+            self.pending & (1 << sig) != 0
+                && (self.saved.is_none() || self.actions[sig].handler <= SIG_IGN)
+        })
+    }
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Make sig pending in proc, waking it up from a sleep or a stop if needed.
+fn send(proc: &mut Process, sig: usize) {
+    if proc.state == ProcState::Exited {
+        return;
+    }
+    // init only takes the signals it handles, it can't be killed
+    if proc.tgid == INIT_PID && proc.signals.actions[sig].handler == SIG_DFL {
+        return;
+    }
+    if sig == SIGCONT || sig == SIGKILL {
+        if proc.state == ProcState::Stopped {
+            proc.set_state(ProcState::Ready);
+        }
+        // a stop waiting to be delivered is cancelled
+        proc.signals.pending &= !(1 << SIGSTOP | 1 << SIGTSTP | 1 << SIGTTIN | 1 << SIGTTOU);
+    }
+    if proc.signals.ignored(sig) {
+        return;
+    }
+    if default_action(sig) == DefaultAction::Stop {
+        proc.signals.pending &= !(1 << SIGCONT);
+    }
+    proc.signals.pending |= 1 << sig;
+    // sleeps are interrupted, see `interrupted`
+    if proc.state == ProcState::Blocked {
+        proc.set_state(ProcState::Ready);
+    }
+}
+
+/// Send sig to the thread pid. Signal 0 only checks that it exists.
+/// Fail with EINVAL if there is no such signal, or ESRCH if there is no
+/// such thread.
+pub fn kill(pid: usize, sig: usize) -> Result<(), Errno> {
+    if sig >= NSIG {
+        return Err(Errno::EINVAL);
+    }
+    let mut pm = PROC_MANAGER.lock();
+    let proc = pm.procs.get_mut(&pid).ok_or(Errno::ESRCH)?;
+    if sig != 0 {
+        send(proc, sig);
+    }
+    Ok(())
+}
+
+/// Send sig to every process but init, e.g. SIGINT on Ctrl-C.
+/// There is no job control, so the shell ignores it.
+pub fn kill_all(sig: usize) {
+    let mut pm = PROC_MANAGER.lock();
+    for proc in pm.procs.values_mut() {
+        if proc.tgid != INIT_PID {
+            send(proc, sig);
+        }
+    }
+}
+
+/// Set what the current thread does with sig, and return the old action.
+/// SIGKILL and SIGSTOP always take the default action, so setting them
+/// fails with EINVAL like an invalid signal.
+pub fn sigaction(sig: usize, action: SigAction) -> Result<SigAction, Errno> {
+    if sig == 0 || sig >= NSIG || sig == SIGKILL || sig == SIGSTOP {
+        return Err(Errno::EINVAL);
+    }
+    let mut pm = PROC_MANAGER.lock();
+    let signals = &mut pm.current().signals;
+    let old = core::mem::replace(&mut signals.actions[sig], action);
+    if signals.ignored(sig) {
+        signals.pending &= !(1 << sig);
+    }
+    Ok(old)
+}
+
+/// Return from a signal handler to the code it interrupted.
+/// Only the user registers and pc are restored, the kernel's view of the
+/// mode stays. Fail with EINVAL if no handler is running.
+pub fn sigreturn(ctx: &mut TrapFrame) -> Result<(), Errno> {
+    let mut pm = PROC_MANAGER.lock();
+    let saved = pm.current().signals.saved.take().ok_or(Errno::EINVAL)?;
+    ctx.regs = saved.regs;
+    ctx.sepc = saved.sepc;
+    Ok(())
+}
+
+/// Whether the current thread has a signal to take, or was killed.
+/// Sleeps that may last long give up then, so it is delivered soon.
+pub fn interrupted() -> bool {
+    PROC_MANAGER.lock().current().interrupted()
+}
+
+/// Deliver the pending signals of the current thread before it returns to
+/// user mode with ctx. Default actions are taken here, and the first
+/// handler found makes ctx call it. A terminating signal ends the whole
+/// process with exit code 128 + sig.
+pub fn deliver(ctx: &mut TrapFrame) {
+    loop {
+        let mut pm = PROC_MANAGER.lock();
+        let proc = pm.current();
+        if let Some(code) = proc.killed {
+            drop(pm);
+            crate::proc::exit(code);
+        }
+        let Some(sig) = proc.signals.next() else {
+            return;
+        };
+        proc.signals.pending &= !(1 << sig);
+        let action = proc.signals.actions[sig];
+        match action.handler {
+            SIG_IGN => {}
+            SIG_DFL => match default_action(sig) {
+                DefaultAction::Terminate => {
+                    let pid = proc.pid;
+                    drop(pm);
+                    info!("pid {}: killed by signal {}", pid, sig);
+                    crate::proc::exit_group(128 + sig as i32);
+                }
+                DefaultAction::Stop => {
+                    proc.set_state(ProcState::Stopped);
+                    // back when SIGCONT or SIGKILL arrives
+                    crate::sched::sched(pm);
+                }
+                DefaultAction::Ignore | DefaultAction::Continue => {}
+            },
+            handler => {
+                proc.signals.saved = Some(*ctx);
+                // handler(sig), returning to the restorer
+                ctx.sepc = handler;
+                ctx.regs[REG_A0] = sig;
+                ctx.regs[REG_RA] = action.restorer;
+                return;
+            }
+        }
+    }
+}
@@ -1,12 +1,40 @@
 #![allow(dead_code)]
 
+use alloc::collections::VecDeque;
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use riscv::register::sstatus;
+
+use crate::proc::{ProcState, Processes, PROC_MANAGER};
 
 pub const LOCKED: bool = true;
 pub const UNLOCKED: bool = false;
 
+/// Disable interrupts, calls nest and are undone by `pop_off`.
+/// An interrupt handler taking a lock held by the code it interrupted would deadlock.
+/// The nesting depth is kept per hart, see `cpu::Cpu`.
+pub fn push_off() {
+    let enabled = sstatus::read().sie();
+    crate::trap::intr_off();
+    let cpu = crate::cpu::mycpu();
+    if cpu.noff == 0 {
+        cpu.intena = enabled;
+    }
+    cpu.noff += 1;
+}
+
+/// Enable interrupts again when the outermost lock is released
+pub fn pop_off() {
+    assert!(!sstatus::read().sie(), "pop_off: interruptible");
+    let cpu = crate::cpu::mycpu();
+    assert!(cpu.noff > 0, "pop_off: no lock held");
+    cpu.noff -= 1;
+    if cpu.noff == 0 && cpu.intena {
+        crate::trap::intr_on();
+    }
+}
+
 #[repr(C)]
 pub struct SpinLock<T> {
     locked: AtomicBool,
@@ -26,6 +54,7 @@ impl<T> SpinLock<T> {
     }
 
     pub fn lock(&self) -> Guard<T> {
+        push_off();
         while self
             .locked
             .compare_exchange_weak(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
@@ -37,6 +66,15 @@ impl<T> SpinLock<T> {
         // debug!("{} acquired", self.name);
         Guard { lock: self }
     }
+
+    /// Release a lock whose guard was forgotten, e.g. when a lock is held
+    /// across a context switch and released by the other side.
+    /// # Safety
+    /// The lock must be held by this hart and no guard of it may be in use.
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(UNLOCKED, Ordering::Release);
+        pop_off();
+    }
 }
 
 pub struct Guard<'a, T> {
@@ -63,16 +101,121 @@ impl<T> Drop for Guard<'_, T> {
     fn drop(&mut self) {
         // debug!("{} released", self.lock.name);
         self.lock.locked.store(UNLOCKED, Ordering::Release);
+        pop_off();
+    }
+}
+
+/// Processes sleeping on a channel until someone wakes them up.
+/// Waking is safe from interrupt handlers, e.g. when a device completes a request.
+pub struct WaitQueue {
+    waiters: SpinLock<VecDeque<usize>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: SpinLock::new(VecDeque::new(), "WaitQueueLock"),
+        }
+    }
+
+    /// Sleep until woken up, releasing the lock of guard meanwhile.
+    /// Wakers must hold the same lock when changing the condition,
+    /// so a wakeup between the check and the sleep can't be missed.
+    /// Return the lock taken again.
+    pub fn sleep<'a, T>(&self, guard: Guard<'a, T>) -> Guard<'a, T> {
+        let lock = guard.lock;
+        let mut pm = PROC_MANAGER.lock();
+        let pid = pm.block_current();
+        self.waiters.lock().push_back(pid);
+        drop(guard);
+        crate::sched::sched(pm);
+        self.forget(pid);
+        lock.lock()
+    }
+
+    /// Like `sleep`, for a condition protected by the process table lock.
+    /// The lock is released while sleeping and not taken again.
+    pub fn sleep_procs(&self, mut pm: Guard<Processes>) {
+        let pid = pm.block_current();
+        self.waiters.lock().push_back(pid);
+        crate::sched::sched(pm);
+        self.forget(pid);
+    }
+
+    /// Sleep until woken up if cond holds. cond is checked with the queue locked,
+    /// so a waker changing the condition before calling `wake_*` is not missed.
+    pub fn wait_if(&self, cond: impl FnOnce() -> bool) {
+        // lock order: process table, then the queue
+        let mut pm = PROC_MANAGER.lock();
+        let mut waiters = self.waiters.lock();
+        if !cond() {
+            return;
+        }
+        let pid = pm.block_current();
+        waiters.push_back(pid);
+        drop(waiters);
+        crate::sched::sched(pm);
+        self.forget(pid);
+    }
+
+    /// Take pid out of the queue once it runs again. It is still there if
+    /// a signal woke it up, and a `wake_one` must not be spent on it.
+    fn forget(&self, pid: usize) {
+        self.waiters.lock().retain(|&p| p != pid);
+    }
+
+    /// Whether nobody sleeps here
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+
+    /// Wake up the longest sleeper, return whether there was one.
+    pub fn wake_one(&self) -> bool {
+        let pid = self.waiters.lock().pop_front();
+        match pid {
+            Some(pid) => {
+                crate::proc::wakeup(pid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake up all sleepers.
+    pub fn wake_all(&self) {
+        let pids: VecDeque<usize> = core::mem::take(&mut self.waiters.lock());
+        for pid in pids {
+            crate::proc::wakeup(pid);
+        }
+    }
+
+    /// Wake up all sleepers, for callers holding the process table lock.
+    pub fn wake_all_procs(&self, pm: &mut Processes) {
+        for pid in self.waiters.lock().drain(..) {
+            if let Some(proc) = pm.procs.get_mut(&pid) {
+                if proc.state == ProcState::Blocked {
+                    proc.set_state(ProcState::Ready);
+                }
+            }
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-/// TODO: use our own os primitive to implement this
+/// Sleeping lock, the process waiting for it gives up the CPU.
+/// Must only be used in process context.
 #[repr(C)]
 pub struct Mutex<T> {
     /// 0: unlocked
     /// 1: locked, no waiters
     /// 2: locked, one or more waiters
     state: AtomicU32,
+    waiters: WaitQueue,
     value: UnsafeCell<T>,
 }
 
@@ -99,6 +242,7 @@ impl<T> Mutex<T> {
     pub const fn new(value: T) -> Self {
         Self {
             state: AtomicU32::new(0),
+            waiters: WaitQueue::new(),
             value: UnsafeCell::new(value),
         }
     }
@@ -110,7 +254,8 @@ impl<T> Mutex<T> {
             .is_err()
         {
             while self.state.swap(2, Ordering::Acquire) != 0 {
-                // wait(&self.state, 2)
+                self.waiters
+                    .wait_if(|| self.state.load(Ordering::Relaxed) == 2);
             }
         }
         MutexGuard { mutex: self }
@@ -120,7 +265,7 @@ impl<T> Mutex<T> {
 impl<T> Drop for MutexGuard<'_, T> {
     fn drop(&mut self) {
         if self.mutex.state.swap(0, Ordering::Release) == 2 {
-            // wake_one(&self.mutex.state);
+            self.mutex.waiters.wake_one();
         }
     }
 }
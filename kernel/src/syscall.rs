@@ -1,82 +1,423 @@
+use crate::fs::File;
+use crate::mm::uaccess::{copy_from_user, copy_str_from_user, copy_to_user};
+use crate::mm::vma::Vma;
+use crate::signal::SigAction;
+use crate::trap::timer::get_time;
 use crate::TrapFrame;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 
-use config::std_io::*;
+use config::errno::Errno;
+use config::fs::MAXPATH;
+use config::layout::{PGSIZE, USER_TOP};
 use config::syscall::*;
+use config::vm::{PTE_R, PTE_W, PTE_X};
 
-pub fn do_syscall(context: &mut TrapFrame) {
-    match context.regs[SYSCALL_REG_NUM] {
-        SYSCALL_EXIT => {
-            let pm = crate::proc::PROC_MANAGER.lock();
-            debug!(
-                "Task {} exited with code: {}",
-                pm.current_pid, context.regs[SYSCALL_REG_RET]
-            );
-            drop(pm);
-            crate::sched::schedule();
+/// The syscalls of the thread whose registers are in context.
+/// Each method implements the syscall of the same name in
+/// `config::syscall_table`, with its arguments already decoded.
+struct Syscall<'a> {
+    context: &'a mut TrapFrame,
+}
+
+macro_rules! dispatch {
+    ($($(#[$attr:meta])* $num:ident = $id:literal => fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty;)*) => {
+        /// Run the syscall in a7 with the arguments in a0-a5.
+        /// The result goes to a0, errors as a negated `Errno`.
+        /// Syscalls of traced processes are logged with their result and
+        /// how many timer ticks they took.
+        pub fn do_syscall(context: &mut TrapFrame) {
+            let traced = {
+                let mut pm = crate::proc::PROC_MANAGER.lock();
+                let proc = pm.current();
+                proc.trace.then_some(proc.pid)
+            };
+            let start = get_time();
+            let regs: [usize; 6] = context.regs[SYSCALL_REG_ARG0..=SYSCALL_REG_ARG5]
+                .try_into()
+                .unwrap();
+            #[allow(unused_mut, unused_variables)]
+            let mut args = regs.into_iter();
+            let (call, ret) = match context.regs[SYSCALL_REG_NUM] {
+                $($num => {
+                    $(let $arg = <$ty as Reg>::from_reg(args.next().unwrap());)*
+                    let call = traced.map(|pid| {
+                        let args: &[String] = &[$(Show::show(&$arg)),*];
+                        let call = format!("{}({})", stringify!($name), args.join(", "));
+                        if matches!($num, SYSCALL_EXIT | SYSCALL_EXIT_GROUP) {
+                            info!("[pid {}] {} = ?", pid, call);
+                        }
+                        call
+                    });
+                    let syscall = Syscall { context: &mut *context };
+                    (call, syscall.$name($($arg),*).map(Reg::to_reg))
+                })*
+                id => {
+                    let pid = crate::proc::PROC_MANAGER.lock().current_pid();
+                    warn!("pid {}: unknown syscall {}", pid, id);
+                    (traced.map(|_| format!("syscall_{}(..)", id)), Err(Errno::ENOSYS))
+                }
+            };
+            if let (Some(pid), Some(call)) = (traced, call) {
+                let ret = show_result(ret);
+                info!("[pid {}] {} = {} <{} ticks>", pid, call, ret, get_time() - start);
+            }
+            context.regs[SYSCALL_REG_RET] = match ret {
+                Ok(value) => value,
+                Err(errno) => errno.to_ret(),
+            };
         }
-        SYSCALL_GETPID => {
-            let pm = crate::proc::PROC_MANAGER.lock();
-            context.regs[SYSCALL_REG_RET] = pm.current_pid;
+    };
+}
+
+/// How syscall arguments and results are shown in traces
+pub trait Show {
+    fn show(&self) -> String;
+}
+
+/// A syscall result in traces, errors by name and description
+pub fn show_result(ret: Result<usize, Errno>) -> String {
+    match ret {
+        Ok(value) => value.show(),
+        Err(errno) => format!("{:?} ({})", errno, errno),
+    }
+}
+
+/// Small values like fds, lengths and flags in decimal,
+/// the others, mostly addresses, in hex
+impl Show for usize {
+    fn show(&self) -> String {
+        if *self < PGSIZE {
+            format!("{}", self)
+        } else {
+            format!("{:#x}", self)
         }
-        SYSCALL_WRITE => {
-            let fd = context.regs[SYSCALL_REG_ARG0];
-            let buf = context.regs[SYSCALL_REG_ARG1] as *const u8;
-            let len = context.regs[SYSCALL_REG_ARG2];
-            let p = buf;
-            unsafe {
-                match fd {
-                    STDOUT | STDERR => {
-                        use core::fmt::Write;
-                        crate::io::Stdout
-                            .write_str(core::str::from_utf8_unchecked(core::slice::from_raw_parts(
-                                p, len,
-                            )))
-                            .unwrap();
-                    }
-                    _ => todo!(
-                        "only support stdout/stderr, which is fd=1/2, but got fd={}",
-                        fd
-                    ),
-                }
+    }
+}
+
+impl Show for isize {
+    fn show(&self) -> String {
+        format!("{}", self)
+    }
+}
+
+impl Show for i32 {
+    fn show(&self) -> String {
+        format!("{}", self)
+    }
+}
+
+config::syscall_table!(dispatch);
+
+impl Syscall<'_> {
+    fn exit(self, code: i32) -> Result<(), Errno> {
+        let pm = crate::proc::PROC_MANAGER.lock();
+        debug!("Task {} exited with code: {}", pm.current_pid(), code);
+        drop(pm);
+        crate::proc::exit(code);
+    }
+
+    fn exit_group(self, code: i32) -> Result<(), Errno> {
+        let mut pm = crate::proc::PROC_MANAGER.lock();
+        debug!("Task {} exited with code: {}", pm.current().tgid, code);
+        drop(pm);
+        crate::proc::exit_group(code);
+    }
+
+    fn clone(self, entry: usize, sp: usize, arg: usize) -> Result<usize, Errno> {
+        crate::proc::clone(entry, sp, arg)
+    }
+
+    fn futex(self, uaddr: usize, op: usize, val: usize) -> Result<usize, Errno> {
+        match op {
+            FUTEX_WAIT => crate::futex::wait(uaddr, val as u32).map(|()| 0),
+            FUTEX_WAKE => crate::futex::wake(uaddr, val),
+            _ => Err(Errno::ENOSYS),
+        }
+    }
+
+    fn waitpid(self, pid: isize, status: usize) -> Result<usize, Errno> {
+        let (child, code) = crate::proc::waitpid(pid)?;
+        if status != 0 {
+            copy_to_user(status, &code.to_ne_bytes())?;
+        }
+        Ok(child)
+    }
+
+    fn sched_yield(self) -> Result<(), Errno> {
+        crate::sched::schedule();
+        Ok(())
+    }
+
+    fn fork(self) -> Result<usize, Errno> {
+        crate::proc::fork()
+    }
+
+    fn exec(self, path: usize, argv: usize) -> Result<usize, Errno> {
+        let (path, args) = exec_args(path, argv)?;
+        let argv: Vec<&str> = args.iter().map(String::as_str).collect();
+        crate::proc::exec(&path, &argv, self.context)
+    }
+
+    fn setpriority(self, pid: usize, nice: i32) -> Result<(), Errno> {
+        crate::proc::setpriority(pid, nice)
+    }
+
+    /// The process, whichever of its threads asks
+    fn getpid(self) -> Result<usize, Errno> {
+        let mut pm = crate::proc::PROC_MANAGER.lock();
+        Ok(pm.current().tgid)
+    }
+
+    fn gettid(self) -> Result<usize, Errno> {
+        let pm = crate::proc::PROC_MANAGER.lock();
+        Ok(pm.current_pid())
+    }
+
+    fn kill(self, pid: usize, sig: usize) -> Result<(), Errno> {
+        crate::signal::kill(pid, sig)
+    }
+
+    fn sigaction(self, sig: usize, handler: usize, restorer: usize) -> Result<usize, Errno> {
+        let action = SigAction { handler, restorer };
+        crate::signal::sigaction(sig, action).map(|old| old.handler)
+    }
+
+    /// a0 is restored with the other registers, and returned as is
+    fn sigreturn(self) -> Result<usize, Errno> {
+        crate::signal::sigreturn(self.context)?;
+        Ok(self.context.regs[SYSCALL_REG_RET])
+    }
+
+    /// Write len bytes from the user address buf to fd.
+    /// They go through a kernel buffer, a chunk at a time.
+    /// Return the number of bytes written, short if something fails midway.
+    fn write(self, fd: usize, buf: usize, len: usize) -> Result<usize, Errno> {
+        let file = crate::proc::getfile(fd)?;
+        let mut file = file.lock();
+        let mut chunk = vec![0; len.min(RWCHUNK)];
+        let mut written = 0;
+        while written < len {
+            let n = (len - written).min(RWCHUNK);
+            let res = copy_from_user(&mut chunk[..n], buf + written);
+            let m = match res.and_then(|()| file.write(&chunk[..n])) {
+                Ok(m) => m,
+                Err(_) if written > 0 => break,
+                Err(errno) => return Err(errno),
+            };
+            written += m;
+            if m < n {
+                break;
             }
-            context.regs[SYSCALL_REG_RET] = len;
         }
-        SYSCALL_READ => {
-            let fd = context.regs[SYSCALL_REG_ARG0];
-            let buf = context.regs[SYSCALL_REG_ARG1] as *mut u8;
-            let len = context.regs[SYSCALL_REG_ARG2];
-            match fd {
-                STDIN => {
-                    let mut cnt = 0;
-                    for i in 0..len {
-                        let ch = unsafe { crate::io::STDIN.pop() };
-                        match ch {
-                            '\r' | '\n' => {
-                                if cnt > 0 {
-                                    break;
-                                }
-                            }
-                            _ => unsafe {
-                                *buf.add(i) = ch as u8;
-                                cnt += 1;
-                            },
-                        }
-                    }
-                    context.regs[SYSCALL_REG_RET] = cnt;
-                }
-                _ => todo!("only support stdin, which is fd=0, but got fd={}", fd),
+        Ok(written)
+    }
+
+    /// Read up to len bytes from fd to the user address buf, see `write`.
+    /// Stop at the first short read, consoles and pipes give what they have.
+    fn read(self, fd: usize, buf: usize, len: usize) -> Result<usize, Errno> {
+        let file = crate::proc::getfile(fd)?;
+        let mut file = Some(file.lock());
+        // the console has no offset to protect, and a reader waiting for a
+        // line must not keep writers of the same file out
+        if matches!(file.as_deref(), Some(File::Console)) {
+            file = None;
+        }
+        let mut chunk = vec![0; len.min(RWCHUNK)];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(RWCHUNK);
+            let res = match &mut file {
+                Some(file) => file.read(&mut chunk[..n]),
+                None => File::read_console(&mut chunk[..n]),
+            };
+            let m = match res.and_then(|m| copy_to_user(buf + done, &chunk[..m]).map(|()| m)) {
+                Ok(m) => m,
+                Err(_) if done > 0 => break,
+                Err(errno) => return Err(errno),
+            };
+            done += m;
+            if m < n {
+                break;
             }
         }
-        SYSCALL_SLEEP => {
-            let time = context.regs[SYSCALL_REG_ARG0];
-            // add to timer list
-            todo!("sleep for {} ticks", time);
+        Ok(done)
+    }
+
+    fn open(self, path: usize, flags: usize) -> Result<usize, Errno> {
+        let path = copy_str_from_user(path, MAXPATH)?;
+        crate::proc::open(&path, flags)
+    }
+
+    fn close(self, fd: usize) -> Result<(), Errno> {
+        crate::proc::close(fd)
+    }
+
+    fn dup(self, fd: usize) -> Result<usize, Errno> {
+        crate::proc::dup(fd)
+    }
+
+    fn dup2(self, oldfd: usize, newfd: usize) -> Result<usize, Errno> {
+        crate::proc::dup2(oldfd, newfd)
+    }
+
+    /// Both fds are closed again if they can't be stored
+    fn pipe(self, fds: usize) -> Result<(), Errno> {
+        let (rfd, wfd) = crate::proc::pipe()?;
+        let mut bytes = [0; 2 * core::mem::size_of::<usize>()];
+        let (r, w) = bytes.split_at_mut(core::mem::size_of::<usize>());
+        r.copy_from_slice(&rfd.to_ne_bytes());
+        w.copy_from_slice(&wfd.to_ne_bytes());
+        copy_to_user(fds, &bytes).inspect_err(|_| {
+            let _ = crate::proc::close(rfd);
+            let _ = crate::proc::close(wfd);
+        })
+    }
+
+    fn sleep(self, ticks: usize) -> Result<(), Errno> {
+        crate::trap::timer::sleep_until(crate::trap::timer::ticks_from_now(ticks))
+            .map_err(|_| Errno::EINTR)
+    }
+
+    fn nanosleep(self, ns: usize, rem: usize) -> Result<(), Errno> {
+        crate::trap::timer::sleep_until(crate::trap::timer::nanos_from_now(ns)).or_else(|left| {
+            if rem != 0 {
+                let left = crate::trap::timer::cycles_to_nanos(left);
+                copy_to_user(rem, &left.to_ne_bytes())?;
+            }
+            Err(Errno::EINTR)
+        })
+    }
+
+    fn sbrk(self, increment: isize) -> Result<usize, Errno> {
+        crate::proc::sbrk(increment)
+    }
+
+    /// File mappings take the inode of fd, whose changes are not written
+    /// back, so they must be private: shared mappings are anonymous only.
+    /// Fail with EACCES if the file was not opened for reading.
+    fn mmap(
+        self,
+        addr: usize,
+        len: usize,
+        prot: usize,
+        flags: usize,
+        fd: usize,
+        offset: usize,
+    ) -> Result<usize, Errno> {
+        check_len(len)?;
+        let perm = prot_to_pte(prot)?;
+        let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+            MAP_SHARED => true,
+            MAP_PRIVATE => false,
+            _ => return Err(Errno::EINVAL),
+        };
+        if len == 0 {
+            return Err(Errno::EINVAL);
         }
-        SYSCALL_GETTIME => {
-            context.regs[SYSCALL_REG_RET] = crate::sbi::get_timer();
+        let addr = (flags & MAP_FIXED != 0).then_some(addr);
+        let file = if flags & MAP_ANONYMOUS == 0 {
+            if shared || !offset.is_multiple_of(PGSIZE) {
+                return Err(Errno::EINVAL);
+            }
+            let file = crate::proc::getfile(fd)?;
+            let file = file.lock();
+            match &*file {
+                File::Inode {
+                    inode,
+                    readable: true,
+                    ..
+                } => Some((*inode, offset)),
+                File::Inode { .. } => return Err(Errno::EACCES),
+                _ => return Err(Errno::ENODEV),
+            }
+        } else {
+            None
+        };
+        crate::proc::mmap(addr, Vma::new(len, perm, shared, file))
+    }
+
+    fn munmap(self, addr: usize, len: usize) -> Result<(), Errno> {
+        check_len(len)?;
+        crate::proc::munmap(addr, len)
+    }
+
+    fn mprotect(self, addr: usize, len: usize, prot: usize) -> Result<(), Errno> {
+        check_len(len)?;
+        crate::proc::mprotect(addr, len, prot_to_pte(prot)?)
+    }
+
+    fn gettime(self) -> Result<usize, Errno> {
+        Ok(crate::sbi::get_timer())
+    }
+
+    /// There is no chdir, processes stay in the root directory.
+    /// Fail with ERANGE if it doesn't fit in size bytes.
+    fn getcwd(self, buf: usize, size: usize) -> Result<usize, Errno> {
+        const CWD: &[u8] = b"/\0";
+        if size < CWD.len() {
+            return Err(Errno::ERANGE);
         }
-        _ => {
-            panic!("unknown syscall number {}", context.regs[SYSCALL_REG_NUM]);
+        copy_to_user(buf, CWD)?;
+        Ok(CWD.len())
+    }
+
+    fn trace(self, on: usize) -> Result<(), Errno> {
+        crate::proc::trace(on != 0);
+        Ok(())
+    }
+}
+
+/// Lengths of user memory ranges fit in user space, so they can be
+/// rounded up to pages.
+fn check_len(len: usize) -> Result<(), Errno> {
+    if len > USER_TOP {
+        return Err(Errno::EINVAL);
+    }
+    Ok(())
+}
+
+/// Translate mmap protections to PTE permissions.
+/// Writable pages must be readable on riscv, and PROT_NONE is not supported.
+fn prot_to_pte(prot: usize) -> Result<usize, Errno> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 || prot == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let mut perm = 0;
+    if prot & (PROT_READ | PROT_WRITE) != 0 {
+        perm |= PTE_R;
+    }
+    if prot & PROT_WRITE != 0 {
+        perm |= PTE_W;
+    }
+    if prot & PROT_EXEC != 0 {
+        perm |= PTE_X;
+    }
+    Ok(perm)
+}
+
+/// Largest piece of a read or write buffered in the kernel at once,
+/// one page so that the buffer never needs contiguous frames
+const RWCHUNK: usize = PGSIZE;
+
+/// Copy the path and the arguments of exec from user memory.
+/// argv is an array of string pointers ending with a null pointer.
+fn exec_args(path: usize, argv: usize) -> Result<(String, Vec<String>), Errno> {
+    let path = copy_str_from_user(path, MAXPATH)?;
+    let mut args = Vec::new();
+    // one more than MAXARG, so that exec sees there are too many
+    while args.len() <= MAXARG {
+        let mut ptr = [0; core::mem::size_of::<usize>()];
+        copy_from_user(&mut ptr, argv + args.len() * core::mem::size_of::<usize>())?;
+        match usize::from_ne_bytes(ptr) {
+            0 => break,
+            arg => match copy_str_from_user(arg, PGSIZE) {
+                Err(Errno::ENAMETOOLONG) => return Err(Errno::E2BIG),
+                arg => args.push(arg?),
+            },
         }
     }
+    Ok((path, args))
 }
@@ -1,7 +1,12 @@
 use core::arch::global_asm;
+use core::fmt;
 use riscv::register::scause::{self, Exception, Interrupt, Trap};
+use riscv::register::stval;
 
-use crate::context::TrapFrame;
+use config::vm::{PTE_R, PTE_W, PTE_X};
+
+use crate::backtrace::Symbol;
+use crate::context::{TrapFrame, REG_RA};
 use crate::trap::plic::{self, ExternalInterrupt};
 
 global_asm!(include_str!("../asm/trap.S"));
@@ -16,10 +21,15 @@ pub fn trap_handler(ctx: &mut TrapFrame) -> &mut TrapFrame {
     );
     match scause.cause() {
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
-            crate::trap::timer::set_next_trigger();
-            #[cfg(feature = "graphics")]
-            crate::io::virtio::gpu::flush().unwrap();
-            // crate::sched::schedule();
+            // the timer also fires early for sleepers due before the next tick
+            if crate::trap::timer::on_interrupt() {
+                #[cfg(feature = "graphics")]
+                crate::io::virtio::gpu::flush().unwrap();
+                // preempt user processes only, the kernel is not reentrant
+                if ctx.from_user() {
+                    crate::sched::tick();
+                }
+            }
         }
         Trap::Interrupt(Interrupt::SupervisorExternal) => {
             let intr = plic::next();
@@ -40,27 +50,117 @@ pub fn trap_handler(ctx: &mut TrapFrame) -> &mut TrapFrame {
                         crate::sbi::console_putchar(ch as u8 as usize);
                     }
                     // push to stdin
-                    unsafe {
-                        crate::io::STDIN.push(ch);
-                    }
+                    crate::io::STDIN.lock().push(ch);
                 }
                 _ => warn!("unimplemented external interrupt {:?}", intr),
             }
             intr.complete()
         }
         Trap::Exception(Exception::UserEnvCall) => {
+            // return to the next instruction
+            ctx.sepc += 4;
             // This is crucial because SIE bit is cleared when exception occurs.
             // To receive ext intrs when handling syscalls, we need to set it again.
             crate::trap::intr_on();
             crate::syscall::do_syscall(ctx);
         }
-        Trap::Exception(Exception::IllegalInstruction) => {
-            panic!("IllegalInstruction")
+        Trap::Exception(
+            e @ (Exception::InstructionPageFault
+            | Exception::LoadPageFault
+            | Exception::StorePageFault),
+        ) => {
+            // Lazy or copy-on-write page of user mode. Retry the instruction.
+            let access = match e {
+                Exception::InstructionPageFault => PTE_X,
+                Exception::LoadPageFault => PTE_R,
+                _ => PTE_W,
+            };
+            page_fault(ctx, stval::read(), access);
         }
-        _ => panic!("unhandled trap {:?}\n{:#x?}", scause.cause(), ctx),
+        Trap::Exception(e) if ctx.from_user() => {
+            // e.g. an illegal instruction or a misaligned access
+            let pid = crate::proc::PROC_MANAGER.lock().current_pid();
+            error!(
+                "pid {}: {:?}, sepc {:#x}, stval {:#x}, killed",
+                pid,
+                e,
+                ctx.sepc,
+                stval::read()
+            );
+            crate::proc::exit(-1);
+        }
+        _ => panic!(
+            "unhandled trap {:?}\n{}",
+            scause.cause(),
+            Dump(ctx, stval::read())
+        ),
     }
-    if scause.is_exception() {
-        ctx.sepc += 4;
+    if ctx.from_user() {
+        crate::signal::deliver(ctx);
+        // switch to the user address space before returning to user mode
+        let mut pm = crate::proc::PROC_MANAGER.lock();
+        crate::mm::vm::activate(pm.current().pagetable);
     }
     ctx
 }
+
+/// Resolve a page fault at a user address through the areas of the current
+/// process. An invalid access kills the process. Faults in the kernel are
+/// bugs, as it reaches user memory through the page table, see mm::uaccess.
+fn page_fault(ctx: &TrapFrame, va: usize, access: usize) {
+    let kind = match access {
+        PTE_X => "instruction fetch at",
+        PTE_R => "load from",
+        _ => "store to",
+    };
+    // PROC_MANAGER may be held by the overflowing code, see trap.S
+    if !ctx.from_user() && crate::mm::kstack::in_guard(va) {
+        let pid = crate::cpu::mycpu().proc.unwrap_or(0);
+        panic!("kernel stack overflow in pid {}\n{}", pid, Dump(ctx, va));
+    }
+    if !ctx.from_user() {
+        panic!("kernel page fault: {} {:#x}\n{}", kind, va, Dump(ctx, va));
+    }
+    let Err(err) = crate::proc::page_fault(va, access) else {
+        return;
+    };
+    let pid = crate::proc::PROC_MANAGER.lock().current_pid();
+    error!(
+        "pid {}: segmentation fault: {} {:#x}: {}, sepc {:#x}, killed",
+        pid, kind, va, err, ctx.sepc
+    );
+    crate::proc::exit(-1);
+}
+
+/// ABI names of the general registers
+const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// The registers of a trap frame and stval, for trap panics
+struct Dump<'a>(&'a TrapFrame, usize);
+
+impl fmt::Display for Dump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Dump(ctx, stval) = *self;
+        writeln!(f, "sepc    {}", Symbol(ctx.sepc))?;
+        writeln!(f, "ra      {}", Symbol(ctx.regs[REG_RA]))?;
+        writeln!(
+            f,
+            "stval   {:#018x}  scause {:#x}  sstatus {:#x}",
+            stval, ctx.scause, ctx.sstatus
+        )?;
+        // four to a line, from ra
+        for (i, name) in REG_NAMES.iter().enumerate().skip(1) {
+            let sep = match i {
+                31 => "",
+                _ if i % 4 == 0 => "\n",
+                _ => "  ",
+            };
+            write!(f, "{:>4} {:#018x}{}", name, ctx.regs[i], sep)?;
+        }
+        Ok(())
+    }
+}
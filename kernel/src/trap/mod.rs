@@ -1,10 +1,10 @@
 mod handler;
 mod plic;
-mod timer;
+pub mod timer;
 
-use riscv::register::{sie, stvec};
+use config::{layout::MMIO_BASE, vm::PA2VA_OFFSET};
+use riscv::register::{sie, sscratch, sstatus, stvec};
 
-#[allow(dead_code)]
 /// turn off interrupt
 pub fn intr_off() {
     unsafe {
@@ -24,12 +24,17 @@ pub fn init(hartid: usize) {
         fn __trap();
     }
     unsafe {
-        stvec::write(__trap as usize, stvec::TrapMode::Direct);
+        // we are in kernel mode, see trap.S
+        sscratch::write(0);
+        stvec::write(__trap as *const () as usize, stvec::TrapMode::Direct);
+        // user memory is only accessed through the page table, see mm::uaccess
+        sstatus::clear_sum();
         sie::set_sext();
         sie::set_stimer();
         plic::init(hartid);
-        (0x10000001 as *mut u8).write_volatile(1);
+        // enable UART receive interrupts
+        ((MMIO_BASE + PA2VA_OFFSET + 1) as *mut u8).write_volatile(1);
         intr_on();
-        timer::set_next_trigger();
+        timer::init();
     }
 }
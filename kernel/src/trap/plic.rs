@@ -1,4 +1,13 @@
-use config::{plic::*, layout::*};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use config::plic::*;
+
+/// Physical address of the PLIC, from the device tree
+static PLIC: AtomicUsize = AtomicUsize::new(config::layout::PLIC_BASE);
+
+fn base() -> usize {
+    PLIC.load(Ordering::Relaxed)
+}
 
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
@@ -34,14 +43,14 @@ impl ExternalInterrupt {
     /// Set the global interrupt source priority
     fn set_priority(&self, priority: u8) -> &Self {
         unsafe {
-            plic_pri(self.as_u32() as usize).write_volatile(priority as u32 & 7);
+            plic_pri(base(), self.as_u32() as usize).write_volatile(priority as u32 & 7);
         }
         self
     }
 
     /// Enable the interrupt source for a specific CPU.
     fn enable(&self, hartid: usize) -> &Self {
-        let enables = plic_sen(hartid);
+        let enables = plic_sen(base(), hartid);
         unsafe {
             enables.write_volatile(enables.read_volatile() | 1 << self.as_u32());
         }
@@ -51,13 +60,14 @@ impl ExternalInterrupt {
     /// Complete the pending interrupt for the current CPU.
     pub fn complete(&self) {
         unsafe {
-            plic_sclaim(cpuid!()).write_volatile(self.as_u32());
+            plic_sclaim(base(), cpuid!()).write_volatile(self.as_u32());
         }
     }
 
 }
 
 pub fn init(hartid: usize) {
+    PLIC.store(crate::dtb::machine().plic.0, Ordering::Relaxed);
     ExternalInterrupt::UART.enable(hartid).set_priority(7);
     ExternalInterrupt::VirtIO(0).enable(hartid).set_priority(7);
     set_threshold(hartid, 0);
@@ -67,11 +77,11 @@ pub fn init(hartid: usize) {
 /// The PLIC will mask any interrupts at or below the given threshold.
 fn set_threshold(hartid: usize, thrs: u8) {
     unsafe {
-        plic_spri(hartid).write_volatile(thrs as u32 & 7)
+        plic_spri(base(), hartid).write_volatile(thrs as u32 & 7)
     }
 }
 
 /// Get the next pending external interrupt for the current CPU.
 pub fn next() -> ExternalInterrupt {
-    ExternalInterrupt::from(unsafe { plic_sclaim(cpuid!()).read_volatile() })
+    ExternalInterrupt::from(unsafe { plic_sclaim(base(), cpuid!()).read_volatile() })
 }
\ No newline at end of file
@@ -1,5 +1,11 @@
+use alloc::collections::BinaryHeap;
+use core::cmp::Reverse;
 use riscv::register::time;
 
+use crate::cpu::mycpu;
+use crate::proc::{ProcState, PROC_MANAGER};
+use crate::sync::SpinLock;
+
 pub fn get_time() -> usize {
     time::read()
 }
@@ -11,8 +17,123 @@ pub fn get_time() -> usize {
 pub const CLOCK_FREQ: usize = 12500000;
 pub const TICKS_PER_SEC: usize = 100;
 // pub const MSEC_PER_TICK: usize = 1000;
+pub const NSEC_PER_SEC: usize = 1_000_000_000;
+
+/// Sleeping processes as (wakeup time, pid), the earliest first.
+/// An entry is only in the queue while its process is in `sleep_until`.
+#[derive(Default)]
+pub struct Sleepers(BinaryHeap<Reverse<(usize, usize)>>);
+
+impl Sleepers {
+    pub const fn new() -> Self {
+        Self(BinaryHeap::new())
+    }
+
+    pub fn push(&mut self, wakeup: usize, pid: usize) {
+        self.0.push(Reverse((wakeup, pid)));
+    }
+
+    /// The earliest wakeup time
+    pub fn next(&self) -> Option<usize> {
+        self.0.peek().map(|&Reverse((wakeup, _))| wakeup)
+    }
+
+    /// Take out the earliest sleeper if it is due at time now, and return its pid.
+    pub fn pop_due(&mut self, now: usize) -> Option<usize> {
+        let &Reverse((wakeup, pid)) = self.0.peek()?;
+        if wakeup > now {
+            return None;
+        }
+        self.0.pop();
+        Some(pid)
+    }
+
+    /// Take out the entry of a sleep that ended early, if still there.
+    pub fn remove(&mut self, wakeup: usize, pid: usize) {
+        self.0.retain(|&Reverse(entry)| entry != (wakeup, pid));
+    }
+}
+
+static SLEEPERS: SpinLock<Sleepers> = SpinLock::new(Sleepers::new(), "SleepersLock");
+
+pub fn init() {
+    mycpu().next_tick = get_time() + CLOCK_FREQ / TICKS_PER_SEC;
+    set_next_trigger();
+}
 
+/// Program the timer of this hart for its next tick,
+/// or earlier if a sleeper is due before it.
 pub fn set_next_trigger() {
-    let time = get_time();
-    crate::sbi::set_timer(time + CLOCK_FREQ / TICKS_PER_SEC);
-}
\ No newline at end of file
+    let mut next = mycpu().next_tick;
+    if let Some(wakeup) = SLEEPERS.lock().next() {
+        next = next.min(wakeup);
+    }
+    crate::sbi::set_timer(next);
+}
+
+/// Handle a timer interrupt: wake up the sleepers whose time has come
+/// and program the next interrupt. Return whether a scheduler tick elapsed.
+pub fn on_interrupt() -> bool {
+    let now = get_time();
+    let cpu = mycpu();
+    let ticked = now >= cpu.next_tick;
+    if ticked {
+        cpu.next_tick = now + CLOCK_FREQ / TICKS_PER_SEC;
+    }
+    // lock order: process table, then sleepers. Holding both, a sleeper
+    // woken up early can't leave `sleep_until` and block on something else
+    // before its entry is popped here.
+    let mut pm = PROC_MANAGER.lock();
+    let mut sleepers = SLEEPERS.lock();
+    while let Some(pid) = sleepers.pop_due(now) {
+        if let Some(proc) = pm.procs.get_mut(&pid) {
+            if proc.state == ProcState::Blocked {
+                proc.set_state(ProcState::Ready);
+            }
+        }
+    }
+    drop(sleepers);
+    drop(pm);
+    set_next_trigger();
+    ticked
+}
+
+/// Block the current process until the timer reaches wakeup.
+/// Other wakeups put it back to sleep, but a signal or `exit_group`
+/// interrupts it: fail then with the timer cycles left.
+pub fn sleep_until(wakeup: usize) -> Result<(), usize> {
+    loop {
+        // checked and queued with the process table locked,
+        // so neither a wakeup nor a signal can be missed
+        let mut pm = PROC_MANAGER.lock();
+        let now = get_time();
+        if now >= wakeup {
+            return Ok(());
+        }
+        if pm.current().interrupted() {
+            return Err(wakeup - now);
+        }
+        let pid = pm.block_current();
+        SLEEPERS.lock().push(wakeup, pid);
+        set_next_trigger();
+        crate::sched::sched(pm);
+        SLEEPERS.lock().remove(wakeup, pid);
+    }
+}
+
+/// Timer value after the given number of scheduler ticks
+pub fn ticks_from_now(ticks: usize) -> usize {
+    get_time().saturating_add(ticks.saturating_mul(CLOCK_FREQ / TICKS_PER_SEC))
+}
+
+/// Nanoseconds taken by the given number of timer cycles
+pub fn cycles_to_nanos(cycles: usize) -> usize {
+    let ns = cycles as u128 * NSEC_PER_SEC as u128 / CLOCK_FREQ as u128;
+    ns.min(usize::MAX as u128) as usize
+}
+
+/// Timer value after the given number of nanoseconds
+pub fn nanos_from_now(ns: usize) -> usize {
+    let cycles = ns as u128 * CLOCK_FREQ as u128 / NSEC_PER_SEC as u128;
+    get_time().saturating_add(cycles.min(usize::MAX as u128) as usize)
+}
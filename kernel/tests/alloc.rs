@@ -0,0 +1,177 @@
+//! Benchmark of the slab heap against the buddy heap it replaced.
+//! Both run the same random mix of allocations and frees, and report
+//! the time taken and the memory they hold beyond what was asked for.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use buddy_system_allocator::Heap;
+use config::layout::PGSIZE;
+use core::alloc::Layout;
+use core::panic::PanicInfo;
+use core::ptr::NonNull;
+use kernel::mm::Slab;
+
+extern crate alloc;
+
+#[no_mangle]
+pub extern "C" fn os_main(_hartid: usize, dtb_pa: usize) -> ! {
+    kernel::dtb::init(dtb_pa);
+    kernel::mm::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+/// Pages given to the buddy heap
+const BUDDY_PAGES: usize = 1024;
+/// Live allocations at most
+const SLOTS: usize = 512;
+const ROUNDS: usize = 20000;
+
+trait Allocator {
+    fn alloc(&mut self, layout: Layout) -> *mut u8;
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout);
+    /// Bytes asked for by live allocations and bytes held for them
+    fn usage(&self) -> (usize, usize);
+}
+
+impl Allocator for Slab {
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        Slab::alloc(self, layout)
+    }
+
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        Slab::dealloc(self, ptr, layout)
+    }
+
+    fn usage(&self) -> (usize, usize) {
+        (self.stats_alloc_user(), self.stats_total_bytes())
+    }
+}
+
+impl Allocator for Heap<32> {
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        Heap::alloc(self, layout).map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr())
+    }
+
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        Heap::dealloc(self, NonNull::new(ptr).unwrap(), layout)
+    }
+
+    fn usage(&self) -> (usize, usize) {
+        (self.stats_alloc_user(), self.stats_alloc_actual())
+    }
+}
+
+/// xorshift, so that both heaps see the same sequence
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as usize
+    }
+}
+
+/// Run the workload, return the time taken in ticks of the time CSR,
+/// and the peak usage as (asked for, held) at the peak of bytes held.
+fn bench(heap: &mut dyn Allocator) -> (usize, (usize, usize)) {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut slots: [Option<(*mut u8, Layout)>; SLOTS] = [None; SLOTS];
+    let mut peak = (0, 0);
+    let start = riscv::register::time::read();
+    for _ in 0..ROUNDS {
+        let slot = &mut slots[rng.next() % SLOTS];
+        match slot.take() {
+            Some((ptr, layout)) => heap.dealloc(ptr, layout),
+            None => {
+                // mostly small objects, some up to two pages
+                let size = match rng.next() % 16 {
+                    0 => 1 + rng.next() % (2 * PGSIZE),
+                    1..=3 => 1 + rng.next() % 1024,
+                    _ => 1 + rng.next() % 128,
+                };
+                let layout = Layout::from_size_align(size, 8).unwrap();
+                let ptr = heap.alloc(layout);
+                assert!(!ptr.is_null(), "out of memory");
+                unsafe { ptr.write_bytes(0xa5, size) };
+                *slot = Some((ptr, layout));
+            }
+        }
+        let usage = heap.usage();
+        if usage.1 > peak.1 {
+            peak = usage;
+        }
+    }
+    let time = riscv::register::time::read() - start;
+    for (ptr, layout) in slots.into_iter().flatten() {
+        heap.dealloc(ptr, layout);
+    }
+    (time, peak)
+}
+
+fn report(name: &str, (time, (user, held)): (usize, (usize, usize))) {
+    kernel::println!(
+        "\n  {}: {} ticks, peak {} KiB held for {} KiB asked, {}% overhead",
+        name,
+        time,
+        held / 1024,
+        user / 1024,
+        (held - user) * 100 / user
+    );
+}
+
+#[test_case]
+fn bench_slab_vs_buddy() {
+    let mut slab = Slab::new();
+    let slab_result = bench(&mut slab);
+
+    let mut buddy = Heap::<32>::new();
+    let region = kernel::mm::alloc_contiguous(BUDDY_PAGES).unwrap();
+    unsafe { buddy.init(region, BUDDY_PAGES * PGSIZE) };
+    let buddy_result = bench(&mut buddy);
+
+    report("slab ", slab_result);
+    report("buddy", buddy_result);
+    // all live objects were freed
+    assert_eq!(slab.stats_alloc_user(), 0);
+    assert_eq!(buddy.stats_alloc_user(), 0);
+}
+
+#[test_case]
+fn test_slab_alignment() {
+    let mut slab = Slab::new();
+    for &size in kernel::mm::SIZE_CLASSES.iter().chain(&[3 * PGSIZE]) {
+        let layout = Layout::from_size_align(size, size.min(PGSIZE)).unwrap();
+        let ptr = slab.alloc(layout);
+        assert_eq!(ptr as usize % layout.align(), 0);
+        slab.dealloc(ptr, layout);
+    }
+    // freed objects are reused
+    let layout = Layout::new::<u64>();
+    let a = slab.alloc(layout);
+    slab.dealloc(a, layout);
+    assert_eq!(slab.alloc(layout), a);
+}
+
+#[test_case]
+fn test_multi_page_reuse() {
+    // more allocations than there are frames never handed out, so the
+    // frames of freed ones must be found together again
+    let free = kernel::mm::free_pages();
+    for i in 0..free / 4 + 16 {
+        let buf = alloc::vec![i as u8; 4 * PGSIZE];
+        assert_eq!(buf[4 * PGSIZE - 1], i as u8);
+    }
+    assert_eq!(kernel::mm::free_pages(), free);
+}
@@ -12,6 +12,7 @@ extern crate alloc;
 
 #[no_mangle]
 pub extern "C" fn os_main(_hartid: usize, dtb_pa: usize) -> ! {
+    kernel::dtb::init(dtb_pa);
     kernel::mm::init();
     kernel::io::init(dtb_pa);
     test_main();
@@ -53,3 +54,117 @@ fn test_virtio_blk_rw() {
     assert_eq!(input, output);
     block::write(0, &origin).unwrap();
 }
+
+#[test_case]
+fn test_symbol_lookup() {
+    use kernel::backtrace::lookup;
+    let map = "ffffffe000200000 _start\n\
+               ffffffe000200100 kernel::proc::exit\n\
+               ffffffe000200180 <kernel::sync::SpinLock<T>>::lock\n";
+    assert_eq!(lookup(map, 0xffffffe000200000), Some(("_start", 0)));
+    assert_eq!(
+        lookup(map, 0xffffffe000200124),
+        Some(("kernel::proc::exit", 0x24))
+    );
+    let lock = lookup(map, 0xffffffe000200190);
+    assert_eq!(lock, Some(("<kernel::sync::SpinLock<T>>::lock", 0x10)));
+    assert_eq!(lookup(map, 0xffffffe0001fffff), None);
+}
+
+#[test_case]
+fn test_backtrace_walk() {
+    extern "C" {
+        fn stext();
+        fn etext();
+    }
+    let text = stext as *const () as usize..etext as *const () as usize;
+    let mut frames = 0;
+    kernel::backtrace::walk(&mut |ra| {
+        assert!(text.contains(&ra));
+        frames += 1;
+    });
+    // this test, the test runner and test_main at least
+    assert!(frames >= 3);
+}
+
+#[test_case]
+fn test_trap_frame_layout() {
+    use kernel::{TrapFrame, REG_A0, REG_RA, REG_SP};
+    // trap.S saves x0-x31, then sstatus, sepc, scause and the hartid
+    assert_eq!(core::mem::size_of::<TrapFrame>(), (32 + 4) * 8);
+    assert_eq!(core::mem::offset_of!(TrapFrame, sepc), 33 * 8);
+    assert_eq!((REG_RA, REG_SP, REG_A0), (1, 2, 10));
+    assert_eq!(REG_A0, config::syscall::SYSCALL_REG_ARG0);
+    let tf = TrapFrame::user(0x1000, 0x2000);
+    assert_eq!((tf.sepc, tf.regs[REG_SP]), (0x1000, 0x2000));
+}
+
+#[test_case]
+fn test_errno() {
+    use config::errno::Errno;
+    for &errno in Errno::ALL.iter() {
+        let ret = errno.to_ret();
+        assert_eq!(ret as isize, -(errno as isize));
+        assert_eq!(Errno::from_ret(ret), Some(errno));
+        assert!(!errno.as_str().is_empty());
+    }
+    // successful results, including large addresses
+    assert_eq!(Errno::from_ret(0), None);
+    assert_eq!(Errno::from_ret(42), None);
+    assert_eq!(Errno::from_ret(0xffff_ffc0_8020_0000), None);
+    assert_eq!(Errno::from_ret(-4096isize as usize), None);
+    // unknown errors
+    assert_eq!(Errno::from_ret(-4095isize as usize), Some(Errno::EINVAL));
+    assert_eq!(Errno::from_ret(-200isize as usize), Some(Errno::EINVAL));
+}
+
+/// (name, number, argument count) of each syscall_table entry
+macro_rules! entries {
+    ($($(#[$attr:meta])* $num:ident = $id:literal => fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty;)*) => {
+        [$((stringify!($name), $id, <[&str]>::len(&[$(stringify!($arg)),*]), config::syscall::$num)),*]
+    };
+}
+
+#[test_case]
+fn test_syscall_table() {
+    let table = config::syscall_table!(entries);
+    for (i, &(name, id, argc, num)) in table.iter().enumerate() {
+        // a0-a5 carry the arguments
+        assert!(argc <= 6, "{} takes {} arguments", name, argc);
+        assert_eq!(id, num, "SYSCALL_* of {}", name);
+        for &(other, other_id, ..) in &table[i + 1..] {
+            assert!(name != other && id != other_id, "{name} and {other} clash");
+        }
+    }
+    let find = |name| table.iter().find(|e| e.0 == name).map(|e| (e.1, e.2));
+    assert_eq!(find("write"), Some((64, 3)));
+    assert_eq!(find("mmap"), Some((222, 6)));
+    assert_eq!(find("nanosleep"), Some((115, 2)));
+    assert_eq!(find("sigreturn"), Some((139, 0)));
+    assert_eq!(find("trace"), Some((402, 1)));
+    assert_eq!(find("sched_yield"), Some((124, 0)));
+    assert_eq!(find("getcwd"), Some((17, 2)));
+    // signed arguments are sign-extended in their register
+    use config::syscall::Reg;
+    assert_eq!((-1i32).to_reg(), usize::MAX);
+    assert_eq!(<i32 as Reg>::from_reg(usize::MAX), -1);
+    assert_eq!(<isize as Reg>::from_reg(-2isize as usize), -2);
+    assert_eq!(().to_reg(), 0);
+}
+
+#[test_case]
+fn test_trace_format() {
+    use config::errno::Errno;
+    use kernel::syscall::{show_result, Show};
+    // small values in decimal, addresses in hex
+    assert_eq!(3usize.show(), "3");
+    assert_eq!(0xfffusize.show(), "4095");
+    assert_eq!(0x1000usize.show(), "0x1000");
+    assert_eq!((-1isize).show(), "-1");
+    assert_eq!((-2i32).show(), "-2");
+    assert_eq!(show_result(Ok(0x3fffffc000)), "0x3fffffc000");
+    assert_eq!(
+        show_result(Err(Errno::EBADF)),
+        "EBADF (bad file descriptor)"
+    );
+}
@@ -6,6 +6,7 @@
 #![test_runner(kernel::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+use config::errno::Errno;
 use config::fs::*;
 use core::panic::PanicInfo;
 use kernel::fs::*;
@@ -14,6 +15,7 @@ extern crate alloc;
 
 #[no_mangle]
 pub extern "C" fn os_main(_hartid: usize, dtb_pa: usize) -> ! {
+    kernel::dtb::init(dtb_pa);
     kernel::mm::init();
     kernel::io::init(dtb_pa);
     kernel::fs::init();
@@ -47,8 +49,9 @@ fn test_root_inode() {
     assert_eq!(root.dinode.major, 0);
     assert_eq!(root.dinode.minor, 0);
     assert_eq!(root.dinode.nlink, 1);
-    assert_eq!(root.dinode.size, 0);
-    assert_eq!(root.dinode.addrs[0], 0);
+    // mkfs copies user programs to the root dir
+    assert_ne!(root.dinode.size, 0);
+    assert!(root.dinode.addrs[0] >= DATA_BLOCK_START as u32);
     let inode_bitmap = Block::read_block(INODE_BITMAP_START);
     assert_eq!(inode_bitmap.get(ROOTINO), 1);
     assert_eq!(inode_bitmap.get(0), 1);
@@ -59,6 +62,164 @@ fn test_namei() {
     // simple test root
     let inode = namei("/").unwrap();
     assert_eq!(inode.inum, 1);
-    assert_eq!(inode.dinode.size, 0);
+    assert_eq!(inode.dinode.typ, FType::Dir);
+    let shell = namei("/shell").unwrap();
+    assert_eq!(shell.dinode.typ, FType::File);
+    assert!(namei("/nothere").is_none());
     // TODO: test more
 }
+
+#[test_case]
+fn test_inode_read() {
+    let shell = namei("/shell").unwrap();
+    let mut magic = [0u8; 4];
+    assert_eq!(shell.read(0, &mut magic), 4);
+    assert_eq!(magic, [0x7f, b'E', b'L', b'F']);
+    // read across block boundaries, and stop at the end of the file
+    let size = shell.dinode.size as usize;
+    let mut buf = [0u8; BSIZE + 10];
+    assert_eq!(shell.read(BSIZE - 5, &mut buf), BSIZE + 10);
+    assert_eq!(shell.read(size - 3, &mut buf), 3);
+    assert_eq!(shell.read(size, &mut buf), 0);
+}
+
+#[test_case]
+fn test_file_write() {
+    use config::syscall::*;
+    assert_eq!(File::open("/nothere", O_RDWR).err(), Some(Errno::ENOENT));
+    // fs.img keeps the file of earlier runs, it starts empty anyway
+    let mut file = File::open("/newfile", O_CREAT | O_RDWR | O_TRUNC).unwrap();
+    assert_eq!(namei("/newfile").unwrap().dinode.size, 0);
+    let data = [7u8; BSIZE + 100];
+    assert_eq!(file.write(&data), Ok(data.len()));
+    // a second open has its own offset
+    let mut again = File::open("/newfile", O_RDONLY).unwrap();
+    let mut buf = [0u8; BSIZE + 200];
+    assert_eq!(again.read(&mut buf), Ok(data.len()));
+    assert_eq!(buf[..data.len()], data);
+    assert_eq!(again.read(&mut buf), Ok(0));
+    assert_eq!(again.write(&data), Err(Errno::EBADF));
+    let inode = namei("/newfile").unwrap();
+    assert_eq!(inode.dinode.size as usize, data.len());
+    // truncated to nothing
+    File::open("/newfile", O_WRONLY | O_TRUNC).unwrap();
+    assert_eq!(namei("/newfile").unwrap().dinode.size, 0);
+    assert_eq!(File::open("/", O_WRONLY).err(), Some(Errno::EISDIR));
+    // a file is not a directory to look up or create in
+    let res = File::open("/newfile/x", O_CREAT | O_RDWR);
+    assert_eq!(res.err(), Some(Errno::ENOENT));
+}
+
+#[test_case]
+fn test_pipe() {
+    use alloc::sync::Arc;
+    let pipe = Arc::new(Pipe::new());
+    let mut rf = File::Pipe {
+        pipe: pipe.clone(),
+        writable: false,
+    };
+    let mut wf = File::Pipe {
+        pipe: pipe.clone(),
+        writable: true,
+    };
+    assert_eq!(wf.write(b"hello"), Ok(5));
+    assert_eq!(rf.write(b"x"), Err(Errno::EBADF));
+    let mut buf = [0u8; 8];
+    assert_eq!(rf.read(&mut buf[..3]), Ok(3));
+    assert_eq!(&buf[..3], b"hel");
+    // buffered data is still read after the write end closes, then EOF
+    drop(wf);
+    assert_eq!(rf.read(&mut buf), Ok(2));
+    assert_eq!(&buf[..2], b"lo");
+    assert_eq!(rf.read(&mut buf), Ok(0));
+    // writing with no reader left fails
+    drop(rf);
+    let mut wf = File::Pipe {
+        pipe,
+        writable: true,
+    };
+    assert_eq!(wf.write(b"x"), Err(Errno::EPIPE));
+}
+
+/// An ELF image with a PT_LOAD header for each (flags, vaddr, memsz)
+/// and no file content.
+fn elf_image(segments: &[(u32, usize, usize)]) -> alloc::vec::Vec<u8> {
+    const EHSIZE: usize = 64;
+    const PHENTSIZE: usize = 56;
+    let mut image = alloc::vec![0u8; EHSIZE + segments.len() * PHENTSIZE];
+    image[..4].copy_from_slice(b"\x7fELF");
+    image[4] = 2;
+    image[18] = 0xf3;
+    image[24..32].copy_from_slice(&0x1000u64.to_le_bytes());
+    image[32..40].copy_from_slice(&(EHSIZE as u64).to_le_bytes());
+    image[54..56].copy_from_slice(&(PHENTSIZE as u16).to_le_bytes());
+    image[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+    for (i, &(flags, vaddr, memsz)) in segments.iter().enumerate() {
+        let ph = &mut image[EHSIZE + i * PHENTSIZE..][..PHENTSIZE];
+        ph[..4].copy_from_slice(&1u32.to_le_bytes());
+        ph[4..8].copy_from_slice(&flags.to_le_bytes());
+        ph[16..24].copy_from_slice(&(vaddr as u64).to_le_bytes());
+        ph[40..48].copy_from_slice(&(memsz as u64).to_le_bytes());
+    }
+    image
+}
+
+/// Write an image made by `elf_image` to /elftest.
+fn write_elf(segments: &[(u32, usize, usize)]) {
+    use config::syscall::*;
+    let mut file = File::open("/elftest", O_CREAT | O_RDWR | O_TRUNC).unwrap();
+    let image = elf_image(segments);
+    assert_eq!(file.write(&image), Ok(image.len()));
+}
+
+/// Load an image made by `elf_image` into a new page table.
+fn load_elf(segments: &[(u32, usize, usize)]) -> Option<(usize, usize)> {
+    use kernel::mm::{vm, vma::VmaList};
+    write_elf(segments);
+    let inode = namei("/elftest").unwrap();
+    let pta = vm::uvmcreate();
+    let res = kernel::loader::load(&inode, pta, &mut VmaList::new());
+    vm::uvmfree(pta);
+    res
+}
+
+#[test_case]
+fn test_load_segments() {
+    use config::layout::*;
+    const R: u32 = 4;
+    const W: u32 = 2;
+    const X: u32 = 1;
+    let text = (R | X, 0x1000, 0x800);
+    // a segment may share its first page with the previous one
+    assert_eq!(
+        load_elf(&[text, (R | W, 0x1800, 0x1000)]),
+        Some((0x1000, 0x3000))
+    );
+    assert_eq!(load_elf(&[(X, 0x1000, 0x800)]), Some((0x1000, 0x2000)));
+    // overlapping or unsorted segments
+    assert_eq!(load_elf(&[text, (R | W, 0x1400, 0x1000)]), None);
+    assert_eq!(load_elf(&[(R | W, 0x3000, 0x1000), text]), None);
+    // over the stack or the mmap area
+    let stack = USTACKTOP - USTACKSIZE;
+    assert_eq!(load_elf(&[text, (R | W, stack, PGSIZE)]), None);
+    assert_eq!(load_elf(&[(R | W, MMAP_TOP - PGSIZE, 2 * PGSIZE)]), None);
+    assert!(load_elf(&[(R | W, MMAP_TOP - PGSIZE, PGSIZE)]).is_some());
+    // no access, or writable but not readable
+    assert_eq!(load_elf(&[(0, 0x1000, 0x800)]), None);
+    assert_eq!(load_elf(&[(W, 0x1000, 0x800)]), None);
+    assert_eq!(load_elf(&[(W | X, 0x1000, 0x800)]), None);
+}
+
+#[test_case]
+fn test_exec_arguments_too_big() {
+    use config::layout::*;
+    write_elf(&[(4 | 1, 0x1000, 0x800)]);
+    let arg = "x".repeat(PGSIZE - 1);
+    let argv = [arg.as_str(); USTACKSIZE / PGSIZE + 1];
+    let mut tf = kernel::TrapFrame::new();
+    // fails before the current process is touched
+    assert_eq!(
+        kernel::proc::exec("/elftest", &argv, &mut tf),
+        Err(Errno::E2BIG)
+    );
+}
@@ -0,0 +1,165 @@
+//! Tests for the scheduling policies, the timer queue and sleeping locks.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::panic::PanicInfo;
+use kernel::proc::{AddrSpace, FdTable, ProcMap, ProcState, Process};
+use kernel::sched::*;
+use kernel::sync::{Mutex, SpinLock, WaitQueue};
+use kernel::trap::timer::Sleepers;
+
+extern crate alloc;
+
+#[no_mangle]
+pub extern "C" fn os_main(_hartid: usize, dtb_pa: usize) -> ! {
+    kernel::dtb::init(dtb_pa);
+    kernel::mm::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+/// Ready processes with pids 1..=n sharing one address space
+fn procs(n: usize) -> ProcMap {
+    let mm = Arc::new(SpinLock::new(AddrSpace::new(), "AddrSpaceLock"));
+    let files = Arc::new(FdTable::new());
+    let mut procs = ProcMap::new();
+    for pid in 1..=n {
+        let proc = Process::new(pid, mm.clone(), files.clone()).unwrap();
+        procs.insert(pid, Box::new(proc));
+    }
+    procs
+}
+
+#[test_case]
+fn test_round_robin() {
+    let mut procs = procs(4);
+    let mut rr = RoundRobin::new();
+    assert_eq!(rr.pick(&procs, 1), Some(2));
+    // wraps around, skipping processes that are not ready
+    assert_eq!(rr.pick(&procs, 4), Some(1));
+    procs.get_mut(&2).unwrap().state = ProcState::Blocked;
+    procs.get_mut(&3).unwrap().state = ProcState::Running;
+    assert_eq!(rr.pick(&procs, 1), Some(4));
+    for pid in [1, 3, 4] {
+        procs.get_mut(&pid).unwrap().state = ProcState::Exited;
+    }
+    assert_eq!(rr.pick(&procs, 2), None);
+    // the last one runs again if alone
+    procs.get_mut(&2).unwrap().state = ProcState::Ready;
+    assert_eq!(rr.pick(&procs, 2), Some(2));
+    assert_eq!(rr.quantum(&procs[&2]), QUANTUM);
+}
+
+#[test_case]
+fn test_priority() {
+    let mut procs = procs(4);
+    let mut prio = Priority::new();
+    procs.get_mut(&1).unwrap().nice = 5;
+    procs.get_mut(&3).unwrap().nice = -5;
+    procs.get_mut(&4).unwrap().nice = -5;
+    // round-robin among the most favored
+    assert_eq!(prio.pick(&procs, 1), Some(3));
+    assert_eq!(prio.pick(&procs, 3), Some(4));
+    assert_eq!(prio.pick(&procs, 4), Some(3));
+    procs.get_mut(&3).unwrap().state = ProcState::Blocked;
+    procs.get_mut(&4).unwrap().state = ProcState::Blocked;
+    assert_eq!(prio.pick(&procs, 4), Some(2));
+}
+
+#[test_case]
+fn test_mlfq() {
+    let mut procs = procs(3);
+    let mut mlfq = Mlfq::new();
+    assert_eq!(mlfq.pick(&procs, 3), Some(1));
+    // using up the quantum demotes down to the last level, and the quantum doubles
+    let proc = procs.get_mut(&1).unwrap();
+    for level in 1..MLFQ_LEVELS + 2 {
+        mlfq.leave(proc, true);
+        assert_eq!(proc.level, level.min(MLFQ_LEVELS - 1));
+        assert_eq!(mlfq.quantum(proc), QUANTUM << proc.level);
+    }
+    // giving up the CPU early keeps the level
+    let proc = procs.get_mut(&2).unwrap();
+    mlfq.leave(proc, false);
+    assert_eq!(proc.level, 0);
+    // higher levels first
+    assert_eq!(mlfq.pick(&procs, 3), Some(2));
+    assert_eq!(mlfq.pick(&procs, 2), Some(3));
+    procs.get_mut(&2).unwrap().state = ProcState::Blocked;
+    procs.get_mut(&3).unwrap().state = ProcState::Blocked;
+    assert_eq!(mlfq.pick(&procs, 3), Some(1));
+    // every level goes back to 0 on a boost
+    for _ in 0..MLFQ_BOOST - 1 {
+        mlfq.tick(&mut procs);
+    }
+    assert_eq!(procs[&1].level, MLFQ_LEVELS - 1);
+    mlfq.tick(&mut procs);
+    assert!(procs.values().all(|proc| proc.level == 0));
+}
+
+#[test_case]
+fn test_sleepers() {
+    let mut sleepers = Sleepers::new();
+    assert_eq!(sleepers.next(), None);
+    sleepers.push(300, 1);
+    sleepers.push(100, 2);
+    sleepers.push(200, 3);
+    sleepers.push(200, 1);
+    assert_eq!(sleepers.next(), Some(100));
+    // the earliest first, and only once due
+    assert_eq!(sleepers.pop_due(99), None);
+    assert_eq!(sleepers.pop_due(250), Some(2));
+    // an interrupted sleep takes out its own entry only
+    sleepers.remove(200, 1);
+    assert_eq!(sleepers.pop_due(250), Some(3));
+    assert_eq!(sleepers.pop_due(250), None);
+    assert_eq!(sleepers.next(), Some(300));
+    sleepers.remove(300, 2);
+    assert_eq!(sleepers.pop_due(usize::MAX), Some(1));
+    assert_eq!(sleepers.next(), None);
+}
+
+#[test_case]
+fn test_wait_queue() {
+    let queue = WaitQueue::new();
+    assert!(queue.is_empty());
+    assert!(!queue.wake_one());
+    queue.wake_all();
+    // the condition is checked before blocking, and there is no process to block here
+    let mut checked = false;
+    queue.wait_if(|| {
+        checked = true;
+        false
+    });
+    assert!(checked);
+    assert!(queue.is_empty());
+}
+
+#[test_case]
+fn test_mutex() {
+    let mutex = Mutex::new(1);
+    // each unlock must leave it free, a locked mutex would block
+    for i in 1..4 {
+        let mut guard = mutex.lock();
+        assert_eq!(*guard, i);
+        *guard += 1;
+    }
+    let a = Mutex::new(0);
+    let b = Mutex::new(0);
+    let (mut ga, mut gb) = (a.lock(), b.lock());
+    *ga += 1;
+    *gb += 2;
+    drop((ga, gb));
+    assert_eq!((*a.lock(), *b.lock()), (1, 2));
+}
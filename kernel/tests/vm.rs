@@ -0,0 +1,404 @@
+//! Tests for user address spaces.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use config::{layout::*, vm::*};
+use core::panic::PanicInfo;
+use kernel::dtb::Regions;
+use kernel::mm::vma::{Vma, VmaList};
+use kernel::mm::{alloc_page, vm};
+
+extern crate alloc;
+
+#[no_mangle]
+pub extern "C" fn os_main(_hartid: usize, dtb_pa: usize) -> ! {
+    kernel::dtb::init(dtb_pa);
+    kernel::mm::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_uvmmap() {
+    let pta = vm::uvmcreate();
+    let page = alloc_page();
+    vm::uvmmap(pta, 0x1000, page - PA2VA_OFFSET, PGSIZE, PTE_R | PTE_W);
+    assert_eq!(vm::walkaddr(pta, 0x1000), Some(page));
+    assert_eq!(vm::walkaddr(pta, 0x1234), Some(page + 0x234));
+    assert_eq!(vm::walkaddr(pta, 0x2000), None);
+    assert_eq!(vm::walkaddr(pta, 0), None);
+}
+
+#[test_case]
+fn test_private_user_mappings() {
+    let pta1 = vm::uvmcreate();
+    let pta2 = vm::uvmcreate();
+    vm::uvmalloc(pta1, USTACKTOP - USTACKSIZE, USTACKSIZE, PTE_R | PTE_W);
+    assert!(vm::walkaddr(pta1, USTACKTOP - 8).is_some());
+    assert_eq!(vm::walkaddr(pta2, USTACKTOP - 8), None);
+}
+
+#[test_case]
+fn test_kernel_half_shared() {
+    let pta = vm::uvmcreate();
+    // switch to the user page table and keep running kernel code
+    vm::activate(pta);
+    let heap_val = alloc::boxed::Box::new(41);
+    assert_eq!(*heap_val, 41);
+    // kernel pages are not accessible from user mode
+    assert_eq!(vm::walkaddr(pta, os_main as *const () as usize), None);
+}
+
+#[test_case]
+fn test_uvmcopy_cow() {
+    let parent = vm::uvmcreate();
+    let child = vm::uvmcreate();
+    vm::uvmalloc(parent, 0x1000, PGSIZE, PTE_R | PTE_W);
+    let page = vm::walkaddr(parent, 0x1000).unwrap();
+    unsafe { *(page as *mut u8) = 42 };
+    vm::uvmcopy(parent, child).unwrap();
+    // both share the same page until one of them writes
+    assert_eq!(vm::walkaddr(child, 0x1000), Some(page));
+    assert_eq!(kernel::mm::page_refcount(page), 2);
+    assert_eq!(vm::cow_fault(child, 0x1000), Ok(true));
+    let copy = vm::walkaddr(child, 0x1000).unwrap();
+    assert_ne!(copy, page);
+    assert_eq!(unsafe { *(copy as *const u8) }, 42);
+    // the parent is the last owner and takes the page back
+    assert_eq!(kernel::mm::page_refcount(page), 1);
+    assert_eq!(vm::cow_fault(parent, 0x1000), Ok(true));
+    assert_eq!(vm::walkaddr(parent, 0x1000), Some(page));
+    assert_eq!(vm::cow_fault(parent, 0x1000), Ok(false));
+}
+
+/// Allocate all free frames but keep, chained through their first word.
+fn take_frames(keep: usize) -> usize {
+    let mut head = 0;
+    while kernel::mm::free_pages() > keep {
+        let page = alloc_page();
+        unsafe { *(page as *mut usize) = head };
+        head = page;
+    }
+    head
+}
+
+/// Free the frames taken by `take_frames`.
+fn give_frames(mut head: usize) {
+    while head != 0 {
+        let next = unsafe { *(head as *const usize) };
+        kernel::mm::free_page(head);
+        head = next;
+    }
+}
+
+#[test_case]
+fn test_uvmcopy_out_of_memory() {
+    let parent = vm::uvmcreate();
+    let child = vm::uvmcreate();
+    // in different gigabytes, each needs two page-table pages in the child
+    vm::uvmalloc(parent, 0x1000, PGSIZE, PTE_R | PTE_W);
+    vm::uvmalloc(parent, 0x4000_1000, PGSIZE, PTE_R | PTE_W);
+    let low = vm::walkaddr(parent, 0x1000).unwrap();
+    let high = vm::walkaddr(parent, 0x4000_1000).unwrap();
+    let taken = take_frames(2);
+    assert_eq!(vm::uvmcopy(parent, child), None);
+    give_frames(taken);
+    // the pages shared before running out are given back to the parent
+    assert_eq!(vm::walkaddr(child, 0x1000), None);
+    assert_eq!(kernel::mm::page_refcount(low), 1);
+    assert_eq!(kernel::mm::page_refcount(high), 1);
+    // a write to the parent's copy-on-write page takes it back
+    assert_eq!(vm::cow_fault(parent, 0x1000), Ok(true));
+    assert_eq!(vm::walkaddr(parent, 0x1000), Some(low));
+}
+
+#[test_case]
+fn test_cow_fault_out_of_memory() {
+    let parent = vm::uvmcreate();
+    let child = vm::uvmcreate();
+    vm::uvmalloc(parent, 0x1000, PGSIZE, PTE_R | PTE_W);
+    vm::uvmcopy(parent, child).unwrap();
+    let taken = take_frames(0);
+    assert_eq!(vm::cow_fault(child, 0x1000), Err("out of memory"));
+    give_frames(taken);
+    // still shared, the write can be retried
+    assert_eq!(vm::useraddr(child, 0x1000, PTE_W), None);
+    assert_eq!(vm::cow_fault(child, 0x1000), Ok(true));
+}
+
+#[test_case]
+fn test_useraddr() {
+    let parent = vm::uvmcreate();
+    let child = vm::uvmcreate();
+    vm::uvmalloc(parent, 0x1000, PGSIZE, PTE_R);
+    vm::uvmalloc(parent, 0x2000, PGSIZE, PTE_R | PTE_W);
+    let page = vm::walkaddr(parent, 0x2000).unwrap();
+    assert!(vm::useraddr(parent, 0x1010, PTE_R).is_some());
+    assert_eq!(vm::useraddr(parent, 0x1010, PTE_W), None);
+    assert_eq!(vm::useraddr(parent, 0x2010, PTE_W), Some(page + 0x10));
+    assert_eq!(vm::useraddr(parent, 0x3000, PTE_R), None);
+    assert_eq!(vm::useraddr(parent, page, PTE_R), None);
+    // copy-on-write pages are readable, and writable once copied
+    vm::uvmcopy(parent, child).unwrap();
+    assert_eq!(vm::useraddr(child, 0x2010, PTE_R), Some(page + 0x10));
+    assert_eq!(vm::useraddr(child, 0x2010, PTE_W), None);
+    assert_eq!(vm::cow_fault(child, 0x2010), Ok(true));
+    assert!(vm::useraddr(child, 0x2010, PTE_W).is_some());
+}
+
+#[test_case]
+fn test_uvmdealloc() {
+    let pta = vm::uvmcreate();
+    assert!(vm::uvmalloc(pta, 0x10000, 2 * PGSIZE, PTE_R | PTE_W).is_some());
+    assert!(vm::walkaddr(pta, 0x11000).is_some());
+    // shrink by one page, like sbrk with a negative increment
+    vm::uvmdealloc(pta, 0x11000, PGSIZE);
+    assert!(vm::walkaddr(pta, 0x10000).is_some());
+    assert_eq!(vm::walkaddr(pta, 0x11000), None);
+}
+
+#[test_case]
+fn test_mmap_munmap() {
+    let pta = vm::uvmcreate();
+    let mut vmas = VmaList::new();
+    let addr = vmas
+        .mmap(
+            pta,
+            None,
+            0x10000,
+            Vma::new(3 * PGSIZE, PTE_R | PTE_W, false, None),
+        )
+        .unwrap();
+    // placed top-down below the stack, mapped zero-filled on the first access
+    assert_eq!(addr, MMAP_TOP - 3 * PGSIZE);
+    assert_eq!(vm::walkaddr(pta, addr + PGSIZE), None);
+    assert!(vmas.fault(pta, addr + PGSIZE + 8, PTE_W).is_ok());
+    let page = vm::walkaddr(pta, addr + PGSIZE).unwrap();
+    assert_eq!(unsafe { *(page as *const u8) }, 0);
+    // unmapping the middle page splits the area
+    vmas.munmap(pta, addr + PGSIZE, PGSIZE);
+    assert_eq!(vm::walkaddr(pta, addr + PGSIZE), None);
+    assert!(vmas.find(addr).is_some_and(|vma| vma.end == addr + PGSIZE));
+    assert!(vmas.find(addr + 2 * PGSIZE).is_some());
+    // the hole can't be protected, the rest can
+    assert!(vmas.mprotect(pta, addr, 3 * PGSIZE, PTE_R).is_none());
+    assert!(vmas.mprotect(pta, addr, PGSIZE, PTE_R).is_some());
+    assert_eq!(vmas.find(addr).unwrap().perm, PTE_R);
+    // the next mapping fills the hole
+    assert_eq!(
+        vmas.mmap(pta, None, 0x10000, Vma::new(PGSIZE, PTE_R, false, None)),
+        Some(addr + PGSIZE)
+    );
+}
+
+#[test_case]
+fn test_mmap_shared_fork() {
+    let parent = vm::uvmcreate();
+    let child = vm::uvmcreate();
+    let mut vmas = VmaList::new();
+    let addr = vmas
+        .mmap(
+            parent,
+            None,
+            0x10000,
+            Vma::new(PGSIZE, PTE_R | PTE_W, true, None),
+        )
+        .unwrap();
+    vm::uvmcopy(parent, child).unwrap();
+    // shared pages are not copied on write
+    let page = vm::walkaddr(parent, addr).unwrap();
+    assert_eq!(vm::walkaddr(child, addr), Some(page));
+    assert_eq!(vm::cow_fault(child, addr), Ok(false));
+}
+
+#[test_case]
+fn test_mmap_fixed_out_of_memory() {
+    let pta = vm::uvmcreate();
+    let mut vmas = VmaList::new();
+    let addr = vmas
+        .mmap(
+            pta,
+            None,
+            0x10000,
+            Vma::new(PGSIZE, PTE_R | PTE_W, false, None),
+        )
+        .unwrap();
+    assert!(vmas.fault(pta, addr, PTE_W).is_ok());
+    let page = vm::walkaddr(pta, addr).unwrap();
+    // a shared area over it with more pages than are free
+    let free = kernel::mm::free_pages();
+    let len = (free + 1) * PGSIZE;
+    let shared = Vma::new(len, PTE_R | PTE_W, true, None);
+    assert_eq!(vmas.mmap(pta, Some(MMAP_TOP - len), 0x10000, shared), None);
+    // the old mapping is left as it was, and no page is lost
+    assert_eq!(vm::walkaddr(pta, addr), Some(page));
+    assert!(vmas.find(addr).is_some_and(|vma| !vma.shared));
+    assert_eq!(kernel::mm::free_pages(), free);
+    // enough frames for the pages but not for a page table they need
+    let boundary = MMAP_TOP & !((1 << 21) - 1);
+    let private = Vma::new(PGSIZE, PTE_R | PTE_W, false, None);
+    assert_eq!(
+        vmas.mmap(pta, Some(boundary), 0x10000, private),
+        Some(boundary)
+    );
+    assert!(vmas.fault(pta, boundary, PTE_W).is_ok());
+    let page = vm::walkaddr(pta, boundary).unwrap();
+    let free = kernel::mm::free_pages();
+    let shared = Vma::new(2 * PGSIZE, PTE_R | PTE_W, true, None);
+    let taken = take_frames(2);
+    assert_eq!(
+        vmas.mmap(pta, Some(boundary - PGSIZE), 0x10000, shared),
+        None
+    );
+    give_frames(taken);
+    assert_eq!(vm::walkaddr(pta, boundary), Some(page));
+    assert!(vmas.find(boundary).is_some_and(|vma| !vma.shared));
+    assert_eq!(kernel::mm::free_pages(), free);
+}
+
+#[test_case]
+fn test_vma_fault() {
+    let pta = vm::uvmcreate();
+    let mut vmas = VmaList::new();
+    let addr = vmas
+        .mmap(pta, None, 0x10000, Vma::new(PGSIZE, PTE_R, false, None))
+        .unwrap();
+    assert_eq!(vmas.fault(pta, addr - 1, PTE_R), Err("address not mapped"));
+    assert_eq!(vmas.fault(pta, addr, PTE_W), Err("permission denied"));
+    assert_eq!(vmas.fault(pta, addr, PTE_X), Err("permission denied"));
+    assert!(vmas.fault(pta, addr, PTE_R).is_ok());
+    assert!(vm::walkaddr(pta, addr).is_some());
+    // spurious, or the page was mapped by another thread in the meantime
+    assert!(vmas.fault(pta, addr, PTE_R).is_ok());
+    assert!(vm::walkaddr(pta, addr).is_some());
+    // a write after the copy-on-write copy was made by another thread
+    let rw = vmas
+        .mmap(
+            pta,
+            None,
+            0x10000,
+            Vma::new(PGSIZE, PTE_R | PTE_W, false, None),
+        )
+        .unwrap();
+    assert!(vmas.fault(pta, rw, PTE_W).is_ok());
+    let child = vm::uvmcreate();
+    vm::uvmcopy(pta, child).unwrap();
+    assert!(vmas.fault(pta, rw, PTE_W).is_ok());
+    let copy = vm::walkaddr(pta, rw);
+    assert!(vmas.fault(pta, rw, PTE_W).is_ok());
+    assert_eq!(vm::walkaddr(pta, rw), copy);
+}
+
+#[test_case]
+fn test_vma_insert_merges() {
+    let mut vmas = VmaList::new();
+    let heap = |start, end| Vma {
+        start,
+        end,
+        perm: PTE_R | PTE_W,
+        shared: false,
+        file: None,
+    };
+    // like a heap grown by sbrk
+    vmas.insert(heap(0x10000, 0x11000));
+    vmas.insert(heap(0x11000, 0x13000));
+    let vma = vmas.find(0x12000).unwrap();
+    assert_eq!((vma.start, vma.end), (0x10000, 0x13000));
+}
+
+#[test_case]
+fn test_frame_refcount() {
+    let free = kernel::mm::free_pages();
+    let page = alloc_page();
+    assert_eq!(kernel::mm::free_pages(), free - 1);
+    assert_eq!(kernel::mm::page_refcount(page), 1);
+    kernel::mm::share_page(page);
+    kernel::mm::free_page(page);
+    // still owned once
+    assert_eq!(kernel::mm::free_pages(), free - 1);
+    kernel::mm::free_page(page);
+    assert_eq!(kernel::mm::free_pages(), free);
+    // freed frames are reused first, zeroed
+    let again = alloc_page();
+    assert_eq!(again, page);
+    assert_eq!(unsafe { *(again as *const usize) }, 0);
+    kernel::mm::free_page(again);
+}
+
+#[test_case]
+fn test_frames_from_device_tree() {
+    extern "C" {
+        fn ekernel();
+    }
+    let machine = kernel::dtb::machine();
+    let ram: usize = machine.memory.iter().map(|(start, end)| end - start).sum();
+    // all of RAM but the firmware, the kernel and the page tables
+    assert!(kernel::mm::free_pages() * PGSIZE > ram - 8 * 1024 * 1024);
+    let page = alloc_page();
+    let pa = page - PA2VA_OFFSET;
+    assert!(page >= ekernel as *const () as usize);
+    assert!(machine.free_memory().iter().any(|(s, e)| pa >= s && pa < e));
+    kernel::mm::free_page(page);
+}
+
+#[test_case]
+fn test_regions() {
+    let mut regions = Regions::new();
+    regions.add(0x3000, 0x4000);
+    regions.add(0x1000, 0x2000);
+    // touching ranges merge
+    regions.add(0x2000, 0x3000);
+    regions.add(0x8000, 0x9000);
+    regions.remove(0x1800, 0x2800);
+    let list: alloc::vec::Vec<_> = regions.iter().collect();
+    assert_eq!(list, [(0x1000, 0x1800), (0x2800, 0x4000), (0x8000, 0x9000)]);
+    assert_eq!(regions.take(0x1000), Some(0x2800));
+    assert_eq!(regions.take(0x1000), Some(0x8000));
+    assert_eq!(regions.take(0x1000), None);
+}
+
+#[test_case]
+fn test_kernel_stack() {
+    use kernel::mm::kstack::{in_guard, KernelStack};
+    // the first stack may allocate page-table pages that are kept
+    drop(KernelStack::new().unwrap());
+    let free = kernel::mm::free_pages();
+    let a = KernelStack::new().unwrap();
+    let b = KernelStack::new().unwrap();
+    assert_eq!(kernel::mm::free_pages(), free - 2 * STACKSIZE / PGSIZE);
+    assert!(a.top().abs_diff(b.top()) >= KSTACK_SLOT);
+    unsafe {
+        let word = (a.top() - STACKSIZE) as *mut usize;
+        assert_eq!(*word, 0);
+        *word = 42;
+        assert_eq!(*word, 42);
+    }
+    assert!(!in_guard(a.top() - 8));
+    assert!(!in_guard(a.top() - STACKSIZE));
+    assert!(in_guard(a.top() - STACKSIZE - 8));
+    drop(a);
+    drop(b);
+    assert_eq!(kernel::mm::free_pages(), free);
+}
+
+#[test_case]
+fn test_contiguous_from_freed_frames() {
+    // more runs than there are frames never handed out, each freed before the next
+    let free = kernel::mm::free_pages();
+    for _ in 0..free / 4 + 16 {
+        let run = kernel::mm::alloc_contiguous(4).expect("freed frames not found");
+        for i in 0..4 {
+            kernel::mm::free_page(run + i * PGSIZE);
+        }
+    }
+    assert_eq!(kernel::mm::free_pages(), free);
+}
@@ -67,6 +67,84 @@ pub struct SuperBlock {
     bmapstart: u32,
 }
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct DirEntry {
+    pub inum: u32,
+    pub name: [u8; DIRSIZ],
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>()) }
+}
+
+fn write_block(fsfd: &mut File, blockno: u32, buf: &[u8]) {
+    fsfd.seek(std::io::SeekFrom::Start(blockno as u64 * BSIZE as u64))
+        .unwrap_or_else(|e| {
+            eprintln!("cannot seek fs.img: {}", e);
+            exit(1);
+        });
+    fsfd.write_all(buf).unwrap_or_else(|e| {
+        eprintln!("cannot write block {} to fs.img: {}", blockno, e);
+        exit(1);
+    });
+}
+
+fn write_inode(fsfd: &mut File, inum: u32, dinode: &DInode) {
+    let address = INDOE_START * BSIZE + inum as usize * std::mem::size_of::<DInode>();
+    fsfd.seek(std::io::SeekFrom::Start(address as u64))
+        .unwrap_or_else(|e| {
+            eprintln!("cannot seek fs.img: {}", e);
+            exit(1);
+        });
+    fsfd.write_all(as_bytes(dinode)).unwrap_or_else(|e| {
+        eprintln!("cannot write inode {} to fs.img: {}", inum, e);
+        exit(1);
+    });
+}
+
+fn alloc_block(freeblock: &mut u32) -> u32 {
+    if *freeblock >= FSSIZE {
+        eprintln!("fs.img is full");
+        exit(1);
+    }
+    *freeblock += 1;
+    *freeblock - 1
+}
+
+/// Copy data to free blocks, and return an on-disk inode for it.
+fn write_file(fsfd: &mut File, data: &[u8], freeblock: &mut u32) -> DInode {
+    if data.len() > MAXFILE * BSIZE {
+        eprintln!("file is too large: {} bytes", data.len());
+        exit(1);
+    }
+    let mut addrs = [0u32; NDIRECT + 1];
+    let mut indirect = [0u32; NINDIRECT];
+    for (i, chunk) in data.chunks(BSIZE).enumerate() {
+        let mut buf = [0u8; BSIZE];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let blockno = alloc_block(freeblock);
+        write_block(fsfd, blockno, &buf);
+        if i < NDIRECT {
+            addrs[i] = blockno;
+        } else {
+            indirect[i - NDIRECT] = blockno;
+        }
+    }
+    if data.len() > NDIRECT * BSIZE {
+        addrs[NDIRECT] = alloc_block(freeblock);
+        write_block(fsfd, addrs[NDIRECT], as_bytes(&indirect));
+    }
+    DInode {
+        typ: FType::File,
+        major: 0,
+        minor: 0,
+        nlink: 1,
+        size: data.len() as u32,
+        addrs,
+    }
+}
+
 fn write_sp(fsfd: &mut File) {
     let sb = SuperBlock {
         magic: FS_MAGIC,
@@ -126,8 +204,36 @@ fn main() {
             exit(1);
         });
     }
+    // write initial files to root dir
+    let mut freeblock = DATA_BLOCK_START as u32;
+    let mut entries = Vec::new();
+    let mut inum = ROOTINO + 1;
+    for name in &args[2..] {
+        let path = format!("../target/riscv64gc-unknown-none-elf/release/{}", name);
+        if std::fs::metadata(&path).is_err() {
+            eprintln!("{} does not exist", path);
+            continue;
+        }
+        let data = std::fs::read(&path).unwrap_or_else(|e| {
+            eprintln!("cannot read {}: {}", path, e);
+            exit(1);
+        });
+        if name.len() > DIRSIZ {
+            eprintln!("file name {} is longer than {}", name, DIRSIZ);
+            exit(1);
+        }
+        let dinode = write_file(&mut fs, &data, &mut freeblock);
+        write_inode(&mut fs, inum, &dinode);
+        let mut entry = DirEntry {
+            inum,
+            name: [0; DIRSIZ],
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        entries.push(entry);
+        inum += 1;
+    }
     // write root inode
-    let dinode = DInode {
+    let mut root = DInode {
         typ: FType::Dir,
         major: 0,
         minor: 0,
@@ -135,53 +241,35 @@ fn main() {
         size: 0,
         addrs: [0; NDIRECT + 1],
     };
-
-    let dinode_bytes = unsafe {
-        std::slice::from_raw_parts(
-            &dinode as *const DInode as *const u8,
-            std::mem::size_of::<DInode>(),
-        )
-    };
-
-    let root_start_address =
-        (INDOE_START) * BSIZE + ROOTINO as usize * std::mem::size_of::<DInode>();
-    fs.seek(std::io::SeekFrom::Start(root_start_address as u64))
-        .unwrap_or_else(|e| {
-            eprintln!("cannot seek fs.img: {}", e);
-            exit(1);
-        });
-    fs.write_all(dinode_bytes).unwrap_or_else(|e| {
-        eprintln!("cannot write root inode to fs.img: {}", e);
-        exit(1);
-    });
-    // write bitmap
-    let bitmap_start_address = INODE_BITMAP_START * BSIZE;
-    fs.seek(std::io::SeekFrom::Start(bitmap_start_address as u64))
-        .unwrap_or_else(|e| {
-            eprintln!("cannot seek fs.img: {}", e);
+    if !entries.is_empty() {
+        // the kernel only looks up entries in the first block
+        if entries.len() * std::mem::size_of::<DirEntry>() > BSIZE {
+            eprintln!("too many files in root dir");
             exit(1);
-        });
+        }
+        let mut buf = [0u8; BSIZE];
+        for (i, entry) in entries.iter().enumerate() {
+            let off = i * std::mem::size_of::<DirEntry>();
+            buf[off..off + std::mem::size_of::<DirEntry>()].copy_from_slice(as_bytes(entry));
+        }
+        root.addrs[0] = alloc_block(&mut freeblock);
+        root.size = (entries.len() * std::mem::size_of::<DirEntry>()) as u32;
+        write_block(&mut fs, root.addrs[0], &buf);
+    }
+    write_inode(&mut fs, ROOTINO, &root);
     // write inode bitmap
     // root inode is used
     // so the second bit is set to 1
     // and the first bit is set to 1 since we don't use it
     let mut bitmap = [0u8; BSIZE];
-    bitmap[0] = 0b0000_0011;
-    fs.write_all(&bitmap).unwrap_or_else(|e| {
-        eprintln!("cannot write bitmap to fs.img: {}", e);
-        exit(1);
-    });
-    // write initial files to root dir
-    // for i in 2..args.len() {
-    //     let path = format!("../target/riscv64gc-unknown-none-elf/release/{}", args[i]);
-    //     if std::fs::metadata(&path).is_err() {
-    //         eprintln!("{} does not exist", path);
-    //         continue;
-    //     }
-    //     let data = std::fs::read(&path).unwrap_or_else(|e| {
-    //         eprintln!("cannot read {}: {}", path, e);
-    //         exit(1);
-    //     });
-    //     let mut name = args[i].clone();
-    // }
+    for i in 0..inum as usize {
+        bitmap[i / 8] |= 1 << (i % 8);
+    }
+    write_block(&mut fs, INODE_BITMAP_START as u32, &bitmap);
+    // write block bitmap, all blocks before freeblock are in use
+    let mut bitmap = [0u8; BSIZE];
+    for i in 0..freeblock as usize {
+        bitmap[i / 8] |= 1 << (i % 8);
+    }
+    write_block(&mut fs, BLOCK_BITMAP_START as u32, &bitmap);
 }
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate ulib;
+
+use ulib::{exec, exit, fork, sleep, wait, Errno};
+
+/// Ticks to wait before retrying after an error, such as fork running out
+/// of memory. init must never exit.
+const RETRY_TICKS: usize = 100;
+
+/// The first user program: start the shell, restart it when it exits,
+/// and reap the orphans handed over by the kernel in the meantime.
+#[no_mangle]
+pub extern "C" fn main() -> i32 {
+    loop {
+        println!("init: starting shell");
+        let pid = match fork() {
+            Ok(0) => {
+                let errno = exec("/shell", &["shell"]);
+                println!("init: exec shell failed: {}", errno);
+                exit(1);
+            }
+            Ok(pid) => pid,
+            Err(errno) => {
+                println!("init: fork failed: {}", errno);
+                let _ = sleep(RETRY_TICKS);
+                continue;
+            }
+        };
+        loop {
+            let mut status = 0;
+            match wait(&mut status) {
+                Ok(wpid) if wpid == pid => break,
+                Ok(_) => {}
+                // the shell is gone somehow, start another one
+                Err(Errno::ECHILD) => break,
+                Err(errno) => {
+                    println!("init: wait returned an error: {}", errno);
+                    let _ = sleep(RETRY_TICKS);
+                }
+            }
+        }
+    }
+}
@@ -4,6 +4,8 @@
 #[macro_use]
 extern crate ulib;
 
+use ulib::{signal, SigHandler, SIGINT};
+
 // #[derive(Debug, Clone, PartialEq, Eq)]
 // pub enum ShellCommand {
 //     /// list files
@@ -82,6 +84,11 @@ extern crate ulib;
 
 #[no_mangle]
 pub extern "C" fn main() -> i32 {
+    // Ctrl-C goes to every process, it must not end the shell.
+    // Children restore the default before exec, ignored signals stay so.
+    if let Err(errno) = signal(SIGINT, SigHandler::Ignore) {
+        println!("shell: signal failed: {}", errno);
+    }
     println!("Hello, RV6!");
     0
 }
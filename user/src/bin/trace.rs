@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate ulib;
+
+use ulib::{args, exec, exit, fork, trace, waitpid};
+
+/// Run a program with its syscalls logged by the kernel:
+/// trace prog [args...]
+/// Exit with its exit code.
+#[no_mangle]
+pub extern "C" fn main() -> i32 {
+    let mut argv = [""; config::syscall::MAXARG];
+    let mut argc = 0;
+    for arg in args().skip(1).take(argv.len()) {
+        argv[argc] = arg;
+        argc += 1;
+    }
+    if argc == 0 {
+        println!("usage: trace prog [args...]");
+        return 1;
+    }
+    let pid = match fork() {
+        Ok(0) => {
+            if let Err(errno) = trace(true) {
+                println!("trace: {}", errno);
+                exit(1);
+            }
+            let errno = exec(argv[0], &argv[..argc]);
+            println!("trace: exec {} failed: {}", argv[0], errno);
+            exit(1);
+        }
+        Ok(pid) => pid,
+        Err(errno) => {
+            println!("trace: fork failed: {}", errno);
+            return 1;
+        }
+    };
+    let mut status = 0;
+    if let Err(errno) = waitpid(pid as isize, &mut status) {
+        println!("trace: wait failed: {}", errno);
+        return 1;
+    }
+    status
+}
@@ -1,34 +1,191 @@
 #![no_std]
-#![feature(panic_info_message)]
 #![feature(linkage)]
 
-use core::arch::asm;
 use core::panic::PanicInfo;
 
-use config::syscall::*;
+use config::fs::MAXPATH;
+use config::syscall::MAXARG;
+
+pub use config::errno::Errno;
+pub use config::signal::{
+    SIGABRT, SIGALRM, SIGCHLD, SIGCONT, SIGHUP, SIGINT, SIGKILL, SIGPIPE, SIGQUIT, SIGSEGV,
+    SIGSTOP, SIGTERM, SIGTSTP, SIGUSR1, SIGUSR2,
+};
+pub use config::syscall::{
+    MAP_ANONYMOUS, MAP_FIXED, MAP_PRIVATE, MAP_SHARED, O_CREAT, O_RDONLY, O_RDWR, O_TRUNC,
+    O_WRONLY, PROT_EXEC, PROT_READ, PROT_WRITE,
+};
 
 mod fs;
+mod signal;
+pub mod sys;
+mod thread;
 
-pub fn syscall(id: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
-    let ret;
-    unsafe {
-        asm!(
-            "ecall",
-            inlateout("a0") arg0 => ret,
-            in("a1") arg1,
-            in("a2") arg2,
-            in("a7") id,
-        );
+pub use signal::{kill, signal, SigHandler};
+pub use sys::{check, syscall};
+pub use thread::{
+    exit_thread, futex_wait, futex_wake, gettid, spawn, Mutex, MutexGuard, Thread,
+    THREAD_STACK_SIZE,
+};
+
+/// Write buffer to fd. Return the number of bytes written.
+pub fn write(fd: usize, buffer: &[u8]) -> Result<usize, Errno> {
+    sys::write(fd, buffer.as_ptr() as usize, buffer.len())
+}
+
+/// Read up to buffer.len() bytes from fd.
+/// Return the number of bytes read, 0 at the end of the file.
+pub fn read(fd: usize, buffer: &mut [u8]) -> Result<usize, Errno> {
+    sys::read(fd, buffer.as_mut_ptr() as usize, buffer.len())
+}
+
+/// Open the file at path with flags, a mix of O_*.
+/// Return the lowest free fd.
+pub fn open(path: &str, flags: usize) -> Result<usize, Errno> {
+    let mut buf = [0u8; MAXPATH];
+    if path.len() >= buf.len() {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    buf[..path.len()].copy_from_slice(path.as_bytes());
+    sys::open(buf.as_ptr() as usize, flags)
+}
+
+/// Release fd.
+pub fn close(fd: usize) -> Result<(), Errno> {
+    sys::close(fd)
+}
+
+/// Make the lowest free fd refer to the file of fd, and return it.
+pub fn dup(fd: usize) -> Result<usize, Errno> {
+    sys::dup(fd)
+}
+
+/// Create a pipe, fds[0] is its read end and fds[1] its write end.
+pub fn pipe(fds: &mut [usize; 2]) -> Result<(), Errno> {
+    sys::pipe(fds.as_mut_ptr() as usize)
+}
+
+/// Make newfd refer to the file of oldfd, closing it first if it was open.
+/// Return newfd.
+pub fn dup2(oldfd: usize, newfd: usize) -> Result<usize, Errno> {
+    sys::dup2(oldfd, newfd)
+}
+
+/// Give up the CPU to the other ready processes.
+pub fn sched_yield() -> Result<(), Errno> {
+    sys::sched_yield()
+}
+
+/// Duplicate the calling process.
+/// Return the child pid in the parent and 0 in the child.
+pub fn fork() -> Result<usize, Errno> {
+    sys::fork()
+}
+
+/// Replace the calling process with the program at path.
+/// The kernel takes '\0' terminated strings, so path and args are copied
+/// to a buffer on the stack first. Return only on failure, with the error.
+pub fn exec(path: &str, args: &[&str]) -> Errno {
+    let mut buf = [0u8; 1024];
+    let mut argv = [0usize; MAXARG + 1];
+    if args.len() > MAXARG {
+        return Errno::E2BIG;
+    }
+    let mut len = 0;
+    for (i, s) in core::iter::once(&path).chain(args).enumerate() {
+        if len + s.len() + 1 > buf.len() {
+            return Errno::E2BIG;
+        }
+        buf[len..len + s.len()].copy_from_slice(s.as_bytes());
+        if i > 0 {
+            argv[i - 1] = buf[len..].as_ptr() as usize;
+        }
+        len += s.len() + 1;
+    }
+    match sys::exec(buf.as_ptr() as usize, argv.as_ptr() as usize) {
+        Ok(_) => unreachable!("exec returned"),
+        Err(errno) => errno,
     }
-    ret
 }
 
-pub fn write(fd: usize, buffer: &[u8]) -> usize {
-    syscall(SYSCALL_WRITE, fd, buffer.as_ptr() as usize, buffer.len())
+/// Wait for the child pid to exit, or any child if pid is -1.
+/// Store its exit code in status and return its pid.
+pub fn waitpid(pid: isize, status: &mut i32) -> Result<usize, Errno> {
+    sys::waitpid(pid, status as *mut i32 as usize)
 }
 
+/// Wait for any child to exit.
+pub fn wait(status: &mut i32) -> Result<usize, Errno> {
+    waitpid(-1, status)
+}
+
+/// Log the syscalls of the calling process on the console if on, or stop.
+/// Its threads, the children it forks and the programs it execs are
+/// traced too.
+pub fn trace(on: bool) -> Result<(), Errno> {
+    sys::trace(on as usize)
+}
+
+/// Set the nice value of process pid, 0 means the caller.
+/// Lower values are scheduled first under the priority policy.
+pub fn setpriority(pid: usize, nice: i32) -> Result<(), Errno> {
+    sys::setpriority(pid, nice)
+}
+
+/// Sleep for the given number of timer ticks, 100 per second.
+/// Fail with EINTR if a signal cuts the sleep short.
+pub fn sleep(ticks: usize) -> Result<(), Errno> {
+    sys::sleep(ticks)
+}
+
+/// Sleep for the given number of nanoseconds. Fail with EINTR if a signal
+/// cuts the sleep short, storing the nanoseconds left in rem if given.
+pub fn nanosleep(ns: usize, rem: Option<&mut usize>) -> Result<(), Errno> {
+    let rem = rem.map_or(0, |rem| rem as *mut usize as usize);
+    sys::nanosleep(ns, rem)
+}
+
+/// Store the current directory in buf and return it.
+/// Fail with ERANGE if buf is too small for it and a '\0'.
+pub fn getcwd(buf: &mut [u8]) -> Result<&str, Errno> {
+    let size = sys::getcwd(buf.as_mut_ptr() as usize, buf.len())?;
+    core::str::from_utf8(&buf[..size - 1]).map_err(|_| Errno::EINVAL)
+}
+
+/// Move the program break by increment bytes. Return the old break.
+pub fn sbrk(increment: isize) -> Result<usize, Errno> {
+    sys::sbrk(increment)
+}
+
+/// Map len bytes of memory with protections prot, a mix of PROT_*,
+/// and flags, MAP_SHARED or MAP_PRIVATE plus MAP_ANONYMOUS or MAP_FIXED.
+/// Files are mapped privately, MAP_SHARED requires MAP_ANONYMOUS.
+/// Return the address of the mapping.
+pub fn mmap(
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> Result<usize, Errno> {
+    sys::mmap(addr, len, prot, flags, fd, offset)
+}
+
+/// Unmap the pages in [addr, addr + len).
+pub fn munmap(addr: usize, len: usize) -> Result<(), Errno> {
+    sys::munmap(addr, len)
+}
+
+/// Change the protections of the mapped pages in [addr, addr + len).
+/// Fail with ENOMEM if part of the range is not mapped.
+pub fn mprotect(addr: usize, len: usize, prot: usize) -> Result<(), Errno> {
+    sys::mprotect(addr, len, prot)
+}
+
+/// End the process, with all its threads.
 pub fn exit(code: i32) -> ! {
-    syscall(SYSCALL_EXIT, code as usize, 0, 0);
+    let _ = sys::exit_group(code);
     panic!("unreachable after sys_exit!")
 }
 
@@ -36,7 +193,7 @@ pub struct DummyWriter;
 
 impl core::fmt::Write for DummyWriter {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        write(1, s.as_bytes());
+        write(1, s.as_bytes()).map_err(|_| core::fmt::Error)?;
         Ok(())
     }
 }
@@ -61,17 +218,37 @@ fn panic(info: &PanicInfo) -> ! {
             "Panicked at {}:{} {}",
             location.file(),
             location.line(),
-            info.message().unwrap()
+            info.message()
         );
     } else {
-        print!("Panicked: {}", info.message().unwrap());
+        print!("Panicked: {}", info.message());
     }
     exit(1)
 }
 
+static mut ARGC: usize = 0;
+static mut ARGV: usize = 0;
+
+/// Command line arguments passed to exec, starting with the program name.
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let argv = unsafe { ARGV } as *const *const u8;
+    (0..unsafe { ARGC }).map(move |i| unsafe {
+        let p = *argv.add(i);
+        let mut len = 0;
+        while *p.add(len) != 0 {
+            len += 1;
+        }
+        core::str::from_utf8_unchecked(core::slice::from_raw_parts(p, len))
+    })
+}
+
 #[no_mangle]
 #[link_section = ".text.entry"]
-pub extern "C" fn _start() -> ! {
+pub extern "C" fn _start(argc: usize, argv: usize) -> ! {
+    unsafe {
+        ARGC = argc;
+        ARGV = argv;
+    }
     exit(main())
 }
 
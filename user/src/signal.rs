@@ -0,0 +1,39 @@
+//! Signals: kill, and handlers installed with `signal`.
+
+use config::signal::*;
+
+use crate::{sys, Errno};
+
+/// What to do with a signal
+#[derive(Clone, Copy)]
+pub enum SigHandler {
+    /// the default action: terminate, ignore or stop depending on the signal
+    Default,
+    Ignore,
+    /// call the function with the signal number, then resume
+    Handler(extern "C" fn(usize)),
+}
+
+/// Send sig to the thread pid, signal 0 only checks it exists.
+pub fn kill(pid: usize, sig: usize) -> Result<(), Errno> {
+    sys::kill(pid, sig)
+}
+
+/// Set what the calling thread does with sig. Other threads keep their
+/// actions, threads and children spawned afterwards get the same.
+/// Fail with EINVAL if sig is not valid or can't be caught.
+pub fn signal(sig: usize, handler: SigHandler) -> Result<(), Errno> {
+    let handler = match handler {
+        SigHandler::Default => SIG_DFL,
+        SigHandler::Ignore => SIG_IGN,
+        SigHandler::Handler(f) => f as usize,
+    };
+    let restorer = sigreturn as extern "C" fn() -> ! as usize;
+    sys::sigaction(sig, handler, restorer).map(|_| ())
+}
+
+/// Handlers return here, and the kernel resumes the interrupted code.
+extern "C" fn sigreturn() -> ! {
+    let _ = sys::sigreturn();
+    unreachable!("sigreturn returned")
+}
@@ -0,0 +1,51 @@
+//! Raw syscalls, one function per entry of `config::syscall_table`.
+//! Arguments are passed as registers, user buffers by address: see the
+//! wrappers at the crate root for safe ones.
+
+use core::arch::asm;
+
+use config::errno::Errno;
+use config::syscall::*;
+
+/// Make syscall id with up to 6 arguments in a0-a5, return a0.
+pub fn syscall(id: usize, args: [usize; 6]) -> usize {
+    let ret;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => ret,
+            in("a1") args[1],
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a5") args[5],
+            in("a7") id,
+        );
+    }
+    ret
+}
+
+/// The result of a syscall from the value it left in a0,
+/// `Err` if it is a negated `Errno`.
+pub fn check(ret: usize) -> Result<usize, Errno> {
+    match Errno::from_ret(ret) {
+        Some(errno) => Err(errno),
+        None => Ok(ret),
+    }
+}
+
+macro_rules! wrappers {
+    ($($(#[$attr:meta])* $num:ident = $id:literal => fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty;)*) => {
+        $(
+            $(#[$attr])*
+            pub fn $name($($arg: $ty),*) -> Result<$ret, Errno> {
+                let regs: &[usize] = &[$(Reg::to_reg($arg)),*];
+                let mut args = [0; 6];
+                args[..regs.len()].copy_from_slice(regs);
+                check(syscall($num, args)).map(Reg::from_reg)
+            }
+        )*
+    };
+}
+
+config::syscall_table!(wrappers);
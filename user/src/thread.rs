@@ -0,0 +1,146 @@
+//! Threads sharing the address space and the open files of the process,
+//! and a mutex for them, built on clone and futex.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use config::syscall::{FUTEX_WAIT, FUTEX_WAKE, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+
+use crate::{mmap, munmap, sys, waitpid, Errno};
+
+/// Stack size of a thread
+pub const THREAD_STACK_SIZE: usize = 16 * 4096;
+
+/// The id of the calling thread, the pid for the first one.
+pub fn gettid() -> usize {
+    sys::gettid().unwrap()
+}
+
+/// Sleep until woken up by `futex_wake` if word still holds val.
+/// Fail with EAGAIN right away if it didn't.
+pub fn futex_wait(word: &AtomicU32, val: u32) -> Result<(), Errno> {
+    let addr = word as *const AtomicU32 as usize;
+    sys::futex(addr, FUTEX_WAIT, val as usize).map(|_| ())
+}
+
+/// Wake up at most n threads sleeping on word, return how many were.
+pub fn futex_wake(word: &AtomicU32, n: usize) -> Result<usize, Errno> {
+    let addr = word as *const AtomicU32 as usize;
+    sys::futex(addr, FUTEX_WAKE, n)
+}
+
+/// End the calling thread only, see `exit` to end the process.
+pub fn exit_thread(code: i32) -> ! {
+    let _ = sys::exit(code);
+    unreachable!("thread exited")
+}
+
+/// A running thread, see `spawn`
+pub struct Thread {
+    tid: usize,
+    stack: usize,
+}
+
+/// Run f(arg) in a new thread with a stack of its own.
+/// Fail if the stack can't be mapped or the thread created.
+pub fn spawn(f: fn(usize) -> i32, arg: usize) -> Result<Thread, Errno> {
+    let stack = mmap(
+        0,
+        THREAD_STACK_SIZE,
+        PROT_READ | PROT_WRITE,
+        MAP_PRIVATE | MAP_ANONYMOUS,
+        0,
+        0,
+    )?;
+    // f and arg go on top of the stack, for thread_start
+    let sp = stack + THREAD_STACK_SIZE - 16;
+    unsafe { *(sp as *mut [usize; 2]) = [f as usize, arg] };
+    let entry = thread_start as extern "C" fn(*const [usize; 2]) -> ! as usize;
+    match sys::clone(entry, sp, sp) {
+        Ok(tid) => Ok(Thread { tid, stack }),
+        Err(errno) => {
+            let _ = munmap(stack, THREAD_STACK_SIZE);
+            Err(errno)
+        }
+    }
+}
+
+extern "C" fn thread_start(args: *const [usize; 2]) -> ! {
+    let [f, arg] = unsafe { *args };
+    let f: fn(usize) -> i32 = unsafe { core::mem::transmute(f) };
+    exit_thread(f(arg))
+}
+
+impl Thread {
+    pub fn tid(&self) -> usize {
+        self.tid
+    }
+
+    /// Wait for the thread to end, free its stack and return its exit code.
+    /// Only the thread that spawned it can join it.
+    pub fn join(self) -> i32 {
+        let mut code = 0;
+        let _ = waitpid(self.tid as isize, &mut code);
+        let _ = munmap(self.stack, THREAD_STACK_SIZE);
+        code
+    }
+}
+
+/// Sleeping lock for threads, waiting in the kernel on a futex.
+pub struct Mutex<T> {
+    /// 0: unlocked
+    /// 1: locked, no waiters
+    /// 2: locked, one or more waiters
+    state: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for Mutex<T> where T: Send {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self
+            .state
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.state.swap(2, Ordering::Acquire) != 0 {
+                let _ = futex_wait(&self.state, 2);
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.mutex.state.swap(0, Ordering::Release) == 2 {
+            let _ = futex_wake(&self.mutex.state, 1);
+        }
+    }
+}